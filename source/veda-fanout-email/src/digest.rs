@@ -0,0 +1,305 @@
+use crate::retry::CHECK_INTERVAL;
+use crate::template::escape_html;
+use chrono::{NaiveDateTime, Utc};
+use lettre::message::Mailbox;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+use v_common::onto::datatype::Lang;
+use v_common::onto::individual::{Individual, RawObj};
+use v_common::onto::individual2msgpack::to_msgpack;
+use v_common::onto::parser::parse_raw;
+
+pub const DIGEST_PATH: &str = "./data/email-digest";
+// Time of the last sending of digests and the attempts of the digests which failed to be sent, one file per recipient
+pub const DIGEST_STATE_PATH: &str = "./data/email-digest-state";
+const LAST_FLUSH: &str = "last_flush";
+
+// Message collected for the digest of recipient
+pub struct DigestItem {
+    pub uri: String,
+    // Version of the message, it is recorded in the sent registry when the digest is sent
+    pub update_counter: Option<i64>,
+    subject: String,
+    body: String,
+    date: i64,
+    path: PathBuf,
+}
+
+// Messages collected for one recipient, they are sent as one email
+pub struct Digest {
+    pub to: Mailbox,
+    pub from: Mailbox,
+    pub items: Vec<DigestItem>,
+    // Number of this attempt to send the digest
+    pub attempt: i64,
+}
+
+impl Digest {
+    pub fn subject(&self, title: &str) -> String {
+        format!("{} ({})", title, self.items.len())
+    }
+
+    // Returns the body and true if it is html, the digest is html if at least one of the messages is html
+    pub fn body(&self) -> (String, bool) {
+        let is_html = self.items.iter().any(|item| is_html(&item.body));
+
+        let mut body = String::new();
+        if is_html {
+            body.push_str("<html><body>");
+            for item in self.items.iter() {
+                body.push_str(&format!("<h3>{}</h3><p><i>{}</i></p>", escape_html(&item.subject), format_date(item.date)));
+                if is_html(&item.body) {
+                    body.push_str(html_content(&item.body));
+                } else {
+                    body.push_str(&format!("<pre>{}</pre>", escape_html(&item.body)));
+                }
+                body.push_str("<hr/>");
+            }
+            body.push_str("</body></html>");
+        } else {
+            for item in self.items.iter() {
+                body.push_str(&format!("{}\n{}\n\n{}\n\n----------\n\n", item.subject, format_date(item.date), item.body));
+            }
+        }
+        (body, is_html)
+    }
+}
+
+// Persistent store of the messages for recipients who receive notifications as a digest. The messages of every recipient are kept
+// in the directory of recipient, one file per message, until the digest is sent. The time of the last sending is stored too,
+// so the restart does not send the digests before the interval
+pub struct DigestStore {
+    interval: i64,
+    last_flush: i64,
+    last_check: Option<Instant>,
+}
+
+impl DigestStore {
+    pub fn new(interval: u64) -> Result<Self, &'static str> {
+        for path in [DIGEST_PATH, DIGEST_STATE_PATH] {
+            if let Err(e) = fs::create_dir_all(path) {
+                error!("failed to create digest store {}, err = {:?}", path, e);
+                return Err("failed to create digest store");
+            }
+        }
+        let last_flush = read_state(LAST_FLUSH).and_then(|mut state| state.get_first_datetime("date")).unwrap_or_else(|| Utc::now().timestamp());
+        Ok(DigestStore {
+            interval: interval as i64,
+            last_flush,
+            last_check: None,
+        })
+    }
+
+    // Adds the message to the digest of recipient, the next adding of the same message replaces it.
+    // The digest is kept by the address only, so the messages to the same address with different names go to one digest
+    pub fn add(&self, to: &Mailbox, from: &Mailbox, uri: &str, update_counter: Option<i64>, subject: &Option<String>, body: &Option<String>) -> bool {
+        let dir = Path::new(DIGEST_PATH).join(recipient_name(to));
+        if let Err(e) = fs::create_dir_all(&dir) {
+            error!("failed to create digest of {}, err = {:?}", to, e);
+            return false;
+        }
+
+        let mut item = Individual::default();
+        item.set_id(uri);
+        item.add_string("to", &to.to_string(), Lang::none());
+        item.add_string("from", &from.to_string(), Lang::none());
+        item.add_string("subject", subject.as_deref().unwrap_or_default(), Lang::none());
+        item.add_string("body", body.as_deref().unwrap_or_default(), Lang::none());
+        item.add_datetime("date", Utc::now().timestamp());
+        if let Some(update_counter) = update_counter {
+            item.add_integer("update_counter", update_counter);
+        }
+
+        let mut raw: Vec<u8> = Vec::new();
+        if let Err(e) = to_msgpack(&item, &mut raw) {
+            error!("failed to serialize digest item, uri = {}, err = {:?}", uri, e);
+            return false;
+        }
+        if let Err(e) = fs::write(dir.join(urlencoding::encode(uri).to_string()), raw) {
+            error!("failed to write digest item, uri = {}, err = {:?}", uri, e);
+            return false;
+        }
+        info!("message is collected for digest, uri = {}, to = {}", uri, to.email);
+        true
+    }
+
+    // Returns the digests of all recipients once per interval and the failed digests whose retry time has come,
+    // the messages stay in the store until remove is called
+    pub fn take_due(&mut self) -> Vec<Digest> {
+        if let Some(last_check) = self.last_check {
+            if last_check.elapsed() < CHECK_INTERVAL {
+                return vec![];
+            }
+        }
+        self.last_check = Some(Instant::now());
+
+        let now = Utc::now().timestamp();
+        let is_flush = now - self.last_flush >= self.interval;
+        if is_flush {
+            self.last_flush = now;
+            let mut state = Individual::default();
+            state.set_id(LAST_FLUSH);
+            state.add_datetime("date", now);
+            write_state(LAST_FLUSH, &state);
+        }
+
+        let dir = match fs::read_dir(DIGEST_PATH) {
+            Ok(dir) => dir,
+            Err(e) => {
+                error!("failed to read digest store, err = {:?}", e);
+                return vec![];
+            },
+        };
+
+        let mut digests = vec![];
+        for recipient_dir in dir.flatten() {
+            let recipient = recipient_dir.file_name().to_string_lossy().to_string();
+            // The failed digest is sent at the time of its retry, the others are sent by the interval
+            let retry = read_state(&recipient);
+            let attempt = match retry {
+                Some(mut retry) => {
+                    if retry.get_first_datetime("next_attempt").unwrap_or_default() > now {
+                        continue;
+                    }
+                    retry.get_first_integer("attempt").unwrap_or_default() + 1
+                },
+                None if is_flush => 1,
+                None => continue,
+            };
+
+            let mut to = match urlencoding::decode(&recipient).ok().and_then(|to| Mailbox::from_str(&to).ok()) {
+                Some(to) => to,
+                None => {
+                    error!("invalid recipient of digest {:?}", recipient_dir.path());
+                    continue;
+                },
+            };
+            let files = match fs::read_dir(recipient_dir.path()) {
+                Ok(files) => files,
+                Err(e) => {
+                    error!("failed to read digest of {}, err = {:?}", to, e);
+                    continue;
+                },
+            };
+
+            let mut from = None;
+            let mut items = vec![];
+            for file in files.flatten() {
+                let path = file.path();
+                let raw = match fs::read(&path) {
+                    Ok(raw) => raw,
+                    Err(e) => {
+                        error!("failed to read digest item {:?}, err = {:?}", path, e);
+                        continue;
+                    },
+                };
+
+                let mut item = Individual::new_raw(RawObj::new(raw));
+                if parse_raw(&mut item).is_err() {
+                    error!("failed to parse digest item {:?}, remove it", path);
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+                if from.is_none() {
+                    from = item.get_first_literal("from").and_then(|from| Mailbox::from_str(&from).ok());
+                }
+                // The name of recipient is taken from the message
+                if to.name.is_none() {
+                    if let Some(named) = item.get_first_literal("to").and_then(|named| Mailbox::from_str(&named).ok()).filter(|named| named.email == to.email) {
+                        to = named;
+                    }
+                }
+                items.push(DigestItem {
+                    uri: item.get_id().to_string(),
+                    update_counter: item.get_first_integer("update_counter"),
+                    subject: item.get_first_literal("subject").unwrap_or_default(),
+                    body: item.get_first_literal("body").unwrap_or_default(),
+                    date: item.get_first_datetime("date").unwrap_or_default(),
+                    path,
+                });
+            }
+
+            if let (Some(from), false) = (from, items.is_empty()) {
+                items.sort_by_key(|item| item.date);
+                digests.push(Digest {
+                    to,
+                    from,
+                    items,
+                    attempt,
+                });
+            }
+        }
+        digests
+    }
+
+    // Keeps the messages of the digest failed with a transient error until next_attempt
+    pub fn schedule(&self, digest: &Digest, next_attempt: i64, err: &str) {
+        let recipient = recipient_name(&digest.to);
+        let mut state = Individual::default();
+        state.set_id(&recipient);
+        state.add_integer("attempt", digest.attempt);
+        state.add_datetime("next_attempt", next_attempt);
+        state.add_string("error", err, Lang::none());
+        write_state(&recipient, &state);
+        warn!("digest is scheduled for retry, to = {}, attempt = {}, err = {}", digest.to.email, digest.attempt, err);
+    }
+
+    // Removes the messages of the sent or undeliverable digest
+    pub fn remove(&self, digest: &Digest) {
+        for item in digest.items.iter() {
+            if let Err(e) = fs::remove_file(&item.path) {
+                error!("failed to remove digest item {:?}, err = {:?}", item.path, e);
+            }
+        }
+        let path = Path::new(DIGEST_STATE_PATH).join(recipient_name(&digest.to));
+        if path.exists() {
+            if let Err(e) = fs::remove_file(&path) {
+                error!("failed to remove digest state {:?}, err = {:?}", path, e);
+            }
+        }
+    }
+}
+
+// Name of the directory of recipient, the digest is kept by the address only
+fn recipient_name(to: &Mailbox) -> String {
+    urlencoding::encode(&to.email.to_string()).to_string()
+}
+
+fn read_state(name: &str) -> Option<Individual> {
+    let raw = fs::read(Path::new(DIGEST_STATE_PATH).join(name)).ok()?;
+    let mut state = Individual::new_raw(RawObj::new(raw));
+    if parse_raw(&mut state).is_err() {
+        error!("failed to parse digest state {}", name);
+        return None;
+    }
+    Some(state)
+}
+
+fn write_state(name: &str, state: &Individual) {
+    let mut raw: Vec<u8> = Vec::new();
+    if let Err(e) = to_msgpack(state, &mut raw) {
+        error!("failed to serialize digest state {}, err = {:?}", name, e);
+        return;
+    }
+    if let Err(e) = fs::write(Path::new(DIGEST_STATE_PATH).join(name), raw) {
+        error!("failed to write digest state {}, err = {:?}", name, e);
+    }
+}
+
+fn is_html(body: &str) -> bool {
+    body.to_lowercase().contains("<html>")
+}
+
+// Content of the body element, the messages are put into the common html document of digest
+fn html_content(body: &str) -> &str {
+    let lower = body.to_ascii_lowercase();
+    let start = lower.find("<body").and_then(|pos| lower[pos..].find('>').map(|end| pos + end + 1)).unwrap_or(0);
+    let end = lower.rfind("</body>").filter(|end| *end >= start).unwrap_or(body.len());
+    &body[start..end]
+}
+
+fn format_date(timestamp: i64) -> String {
+    NaiveDateTime::from_timestamp(timestamp, 0).format("%d.%m.%Y %H:%M").to_string()
+}
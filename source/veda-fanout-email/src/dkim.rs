@@ -0,0 +1,46 @@
+use lettre::message::dkim::{DkimConfig, DkimSigningAlgorithm, DkimSigningKey};
+use std::fs;
+use v_common::onto::individual::Individual;
+
+// Reads the DKIM settings of connection, returns None if the connection has no selector, messages are sent unsigned then.
+// An incomplete configuration or unreadable key is an error, the relay rejects unsigned messages of the signed domain
+pub fn load_dkim_config(connection: &mut Individual) -> Result<Option<DkimConfig>, &'static str> {
+    let selector = connection.get_first_literal("v-s:dkim_selector").unwrap_or_default();
+    if selector.is_empty() {
+        return Ok(None);
+    }
+
+    let domain = connection.get_first_literal("v-s:dkim_domain").unwrap_or_default();
+    let key_path = connection.get_first_literal("v-s:dkim_private_key").unwrap_or_default();
+    if domain.is_empty() || key_path.is_empty() {
+        error!("parameters [dkim_domain] and [dkim_private_key] are required for dkim selector {}", selector);
+        return Err("incomplete dkim configuration");
+    }
+
+    let algorithm = match connection.get_first_literal("v-s:dkim_algorithm").unwrap_or_default().to_lowercase().as_str() {
+        "" | "rsa" => DkimSigningAlgorithm::Rsa,
+        "ed25519" => DkimSigningAlgorithm::Ed25519,
+        other => {
+            error!("unknown dkim algorithm {}, expected rsa or ed25519", other);
+            return Err("unknown dkim algorithm");
+        },
+    };
+
+    let key = match fs::read_to_string(&key_path) {
+        Ok(key) => key,
+        Err(e) => {
+            error!("failed to read dkim private key {}, err = {:?}", key_path, e);
+            return Err("failed to read dkim private key");
+        },
+    };
+    let signing_key = match DkimSigningKey::new(&key, algorithm) {
+        Ok(signing_key) => signing_key,
+        Err(e) => {
+            error!("failed to load dkim private key {}, err = {:?}", key_path, e);
+            return Err("failed to load dkim private key");
+        },
+    };
+
+    info!("messages are signed by dkim, selector = {}, domain = {}", selector, domain);
+    Ok(Some(DkimConfig::default_config(selector, domain, signing_key)))
+}
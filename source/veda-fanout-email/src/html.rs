@@ -0,0 +1,199 @@
+// Plain text version of the html body, it is sent together with html as multipart/alternative.
+// Blocks and line breaks become new lines, links keep their address in brackets, the content of head, style and script is dropped
+pub fn html_to_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut skip_until: Option<String> = None;
+    let mut link: Option<String> = None;
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        if skip_until.is_none() {
+            push_text(&mut text, &rest[..start]);
+        }
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => {
+                rest = &rest[start..];
+                break;
+            },
+        };
+        let tag = &rest[start + 1..end];
+        rest = &rest[end + 1..];
+
+        let is_closing = tag.starts_with('/');
+        let name = tag.trim_start_matches('/').split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or_default().to_ascii_lowercase();
+
+        if let Some(until) = &skip_until {
+            if is_closing && &name == until {
+                skip_until = None;
+            }
+            continue;
+        }
+
+        match name.as_str() {
+            "head" | "style" | "script" | "title" if !is_closing => skip_until = Some(name),
+            "br" => text.push('\n'),
+            "p" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "table" | "ul" | "ol" | "blockquote" => text.push_str("\n\n"),
+            "div" | "tr" | "hr" => text.push('\n'),
+            "li" if !is_closing => text.push_str("\n* "),
+            "td" | "th" if is_closing => text.push(' '),
+            "a" if !is_closing => link = attribute(tag, "href"),
+            "a" => {
+                if let Some(href) = link.take() {
+                    if !href.is_empty() && !href.starts_with('#') && !href.starts_with("cid:") {
+                        text.push_str(&format!(" ({})", href));
+                    }
+                }
+            },
+            "img" => {
+                if let Some(alt) = attribute(tag, "alt").filter(|alt| !alt.is_empty()) {
+                    text.push_str(&format!("[{}]", alt));
+                }
+            },
+            _ => {},
+        }
+    }
+    if skip_until.is_none() {
+        push_text(&mut text, rest);
+    }
+
+    // Lines are trimmed, more than one empty line in a row is removed
+    let mut result = String::with_capacity(text.len());
+    let mut empty_lines = 0;
+    for line in text.lines().map(|line| line.trim()) {
+        if line.is_empty() {
+            empty_lines += 1;
+            if empty_lines > 1 || result.is_empty() {
+                continue;
+            }
+        } else {
+            empty_lines = 0;
+        }
+        result.push_str(line);
+        result.push('\n');
+    }
+    result.trim_end().to_owned()
+}
+
+// The whitespaces of html source are collapsed into one space as a browser does
+fn push_text(text: &mut String, html: &str) {
+    let decoded = decode_entities(html);
+    let mut prev_space = text.ends_with(|c: char| c.is_whitespace());
+    for c in decoded.chars() {
+        if c.is_whitespace() && c != '\u{a0}' {
+            if !prev_space {
+                text.push(' ');
+                prev_space = true;
+            }
+        } else {
+            text.push(if c == '\u{a0}' {
+                ' '
+            } else {
+                c
+            });
+            prev_space = false;
+        }
+    }
+}
+
+fn decode_entities(html: &str) -> String {
+    let mut result = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('&') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = match rest.find(';') {
+            Some(end) if end <= 10 => end,
+            _ => {
+                result.push('&');
+                rest = &rest[1..];
+                continue;
+            },
+        };
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            _ if entity.starts_with("#x") || entity.starts_with("#X") => u32::from_str_radix(&entity[2..], 16).ok().and_then(char::from_u32),
+            _ if entity.starts_with('#') => entity[1..].parse::<u32>().ok().and_then(char::from_u32),
+            _ => None,
+        };
+        match decoded {
+            Some(c) => {
+                result.push(c);
+                rest = &rest[end + 1..];
+            },
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            },
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(pos) = lower[from..].find(name) {
+        let pos = from + pos;
+        from = pos + name.len();
+        if pos > 0 && !lower[..pos].ends_with(|c: char| c.is_whitespace()) {
+            continue;
+        }
+        let value = lower[from..].trim_start();
+        if !value.starts_with('=') {
+            continue;
+        }
+        let value_start = tag.len() - value[1..].trim_start().len();
+        let value = &tag[value_start..];
+        return Some(match value.chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => decode_entities(value[1..].split(quote).next().unwrap_or_default()),
+            _ => decode_entities(value.split(|c: char| c.is_whitespace()).next().unwrap_or_default()),
+        });
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_to_text_keeps_blocks_and_links() {
+        let html = "<html><head><title>Title</title><style>p { color: red; }</style></head><body>\
+                    <p>Hello,   <b>world</b></p><p>See <a href=\"https://example.com/doc\">the document</a><br/>Thanks</p></body></html>";
+        assert_eq!(html_to_text(html), "Hello, world\n\nSee the document (https://example.com/doc)\nThanks");
+    }
+
+    #[test]
+    fn html_to_text_lists_and_images() {
+        let html = "<ul><li>one</li><li>two</li></ul><img src=\"cid:logo\" alt=\"Logo\">";
+        assert_eq!(html_to_text(html), "* one\n* two\n\n[Logo]");
+    }
+
+    #[test]
+    fn html_to_text_skips_anchor_and_cid_links() {
+        assert_eq!(html_to_text("<a href='#top'>top</a> <a href=\"cid:img\">image</a>"), "top image");
+    }
+
+    #[test]
+    fn decode_entities_named_and_numeric() {
+        assert_eq!(decode_entities("a &amp; b &lt;c&gt; &quot;d&quot; &apos;e&apos;"), "a & b <c> \"d\" 'e'");
+        assert_eq!(decode_entities("&#1071; &#x42F;"), "Я Я");
+        assert_eq!(decode_entities("&nbsp;"), "\u{a0}");
+    }
+
+    #[test]
+    fn decode_entities_keeps_unknown() {
+        assert_eq!(decode_entities("AT&T"), "AT&T");
+        assert_eq!(decode_entities("&unknown; &"), "&unknown; &");
+        assert_eq!(decode_entities("&#xZZ;"), "&#xZZ;");
+    }
+}
@@ -2,10 +2,32 @@
 #[macro_use]
 extern crate log;
 
-use lettre::message::{header, Mailbox, MultiPart, SinglePart};
+mod digest;
+mod dkim;
+mod html;
+mod retry;
+mod sent;
+mod status;
+mod template;
+mod transport;
+mod undeliverable;
+
+use crate::digest::{Digest, DigestStore};
+use crate::dkim::load_dkim_config;
+use crate::html::html_to_text;
+use crate::retry::RetryQueue;
+use crate::sent::SentRegistry;
+use crate::status::{is_status_update, write_status, DeliveryReport, DeliveryStatus};
+use crate::template::{escape_html, render_message};
+use crate::transport::{LocalFormat, LocalTransport, MailTransport};
+use crate::undeliverable::UndeliverableQueue;
+use lettre::message::dkim::DkimConfig;
+use lettre::message::{header, Attachment, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::response::Code;
 use lettre::transport::smtp::PoolConfig;
 use lettre::{Address, Message, SmtpTransport, Transport};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::env;
 use std::error::Error;
 use std::str::FromStr;
 use std::time::Duration;
@@ -23,11 +45,48 @@ const ATTACHMENTS_DB_PATH: &str = "data/files";
 
 pub struct Context {
     onto: Onto,
-    smtp_client: Option<SmtpTransport>,
+    transport: Option<MailTransport>,
+    dkim: Option<DkimConfig>,
+    max_attachment_size: Option<u64>,
+    max_message_size: Option<u64>,
+    app_url: String,
     default_mail_sender: String,
     always_use_mail_sender: bool,
     sys_ticket: String,
     module_info: ModuleInfo,
+    retry_queue: RetryQueue,
+    undeliverable: UndeliverableQueue,
+    sent: SentRegistry,
+    // The messages recorded in the sent registry are sent again, it is used to replay a range of the queue
+    force_delivery: bool,
+    digest: DigestStore,
+    digest_subject: String,
+}
+
+// Result of sending of the message individual
+enum DeliveryResult {
+    Sent {
+        // Local transports have no SMTP response
+        smtp_code: Option<i64>,
+        message_id: Option<String>,
+        // Attachments over the size limits, they are sent as links
+        linked_attachments: Vec<String>,
+    },
+    // The message is deleted or is a draft
+    Ignored,
+    // The message is collected for the digests of all its recipients
+    Digested,
+    // Every recipient rejected messages of the type by preferences
+    Skipped,
+    // The error may be resolved by sending again later
+    TransientError {
+        smtp_code: Option<i64>,
+        text: String,
+    },
+    PermanentError {
+        smtp_code: Option<i64>,
+        text: String,
+    },
 }
 
 fn main() -> Result<(), i32> {
@@ -43,20 +102,69 @@ fn main() -> Result<(), i32> {
         return Err(-1);
     }
 
+    // Transient failures are sent again after base_delay, the delay is doubled after every next failure up to max_delay
+    let retry_max_attempts = Module::get_property::<String>("retry_max_attempts").and_then(|v| v.parse::<i64>().ok()).unwrap_or(10);
+    let retry_base_delay = Module::get_property::<String>("retry_base_delay").and_then(|v| v.parse::<i64>().ok()).unwrap_or(60);
+    let retry_max_delay = Module::get_property::<String>("retry_max_delay").and_then(|v| v.parse::<i64>().ok()).unwrap_or(6 * 60 * 60);
+    let retry_queue = match RetryQueue::new(retry_max_attempts, retry_base_delay, retry_max_delay) {
+        Ok(queue) => queue,
+        Err(_) => return Err(-1),
+    };
+    let undeliverable = match UndeliverableQueue::new("fanout_email0") {
+        Ok(queue) => queue,
+        Err(_) => return Err(-1),
+    };
+    let sent = match SentRegistry::new() {
+        Ok(registry) => registry,
+        Err(_) => return Err(-1),
+    };
+    let force_delivery = env::args().any(|arg| arg == "--force_delivery");
+    if force_delivery {
+        warn!("force delivery, the messages which were sent already are sent again");
+    }
+
+    // Notifications for the recipients who opted in to the digest are sent once per digest_interval
+    let digest_interval = Module::get_property::<String>("digest_interval").and_then(|v| v.parse::<u64>().ok()).unwrap_or(60 * 60);
+    let digest = match DigestStore::new(digest_interval) {
+        Ok(store) => store,
+        Err(_) => return Err(-1),
+    };
+    let digest_subject = Module::get_property::<String>("digest_subject").unwrap_or_else(|| "Notifications digest".to_owned());
+
     let mut module = Module::default();
     let mut backend = Backend::default();
     let systicket = backend.get_sys_ticket_id();
 
     let mut ctx = Context {
         onto: Onto::default(),
-        smtp_client: None,
+        transport: None,
+        dkim: None,
+        max_attachment_size: None,
+        max_message_size: None,
+        app_url: String::default(),
         default_mail_sender: String::default(),
         always_use_mail_sender: false,
         sys_ticket: systicket.unwrap_or_default(),
         module_info: module_info.unwrap(),
+        retry_queue,
+        undeliverable,
+        sent,
+        force_delivery,
+        digest,
+        digest_subject,
     };
 
-    connect_to_smtp(&mut ctx, &mut backend);
+    // Messages can not be sent without the transport, so the configuration error stops the module instead of failing every message
+    if !connect_to_smtp(&mut ctx, &mut backend) {
+        error!("failed to configure mail transport, check the connection in v-s:send_an_email_individual_by_event of cfg:standart_node");
+        return Err(-1);
+    }
+
+    // Attachments over the size limits are sent as links to the web application
+    ctx.app_url = backend.get_individual("v-s:vedaInfo", &mut Individual::default()).and_then(|info| info.get_first_literal("v-s:appUrl")).unwrap_or_default();
+    if ctx.app_url.is_empty() && (ctx.max_attachment_size.is_some() || ctx.max_message_size.is_some()) {
+        warn!("v-s:vedaInfo has no v-s:appUrl, links to large attachments will be incomplete");
+    }
 
     info!("load ontology start");
     load_onto(&mut backend.storage, &mut ctx.onto);
@@ -74,10 +182,196 @@ fn main() -> Result<(), i32> {
     Ok(())
 }
 
-fn heartbeat(_module: &mut Backend, _ctx: &mut Context) -> Result<(), PrepareError> {
+fn heartbeat(backend: &mut Backend, ctx: &mut Context) -> Result<(), PrepareError> {
+    retry_due(backend, ctx);
+    send_digests(backend, ctx);
     Ok(())
 }
 
+// Sends the collected messages as one email per recipient
+fn send_digests(backend: &mut Backend, ctx: &mut Context) {
+    for digest in ctx.digest.take_due() {
+        let subject = digest.subject(&ctx.digest_subject);
+        let (body, is_html) = digest.body();
+
+        let message_builder = Message::builder().from(digest.from.clone()).to(digest.to.clone()).subject(subject.clone());
+        let email = if is_html {
+            message_builder.multipart(html_body(&body, vec![]))
+        } else {
+            message_builder.header(header::ContentType::parse("text/plain; charset=utf-8").unwrap()).header(header::ContentTransferEncoding::QuotedPrintable).body(body)
+        };
+        let res = match email {
+            Ok(email) => send_email(ctx, email, &format!("digest:{}", digest.to.email), &Some(subject), false),
+            Err(e) => {
+                error!("Failed to build digest for {}, error: {}", digest.to.email, e);
+                DeliveryResult::PermanentError {
+                    smtp_code: None,
+                    text: e.to_string(),
+                }
+            },
+        };
+        handle_digest_result(backend, ctx, &digest, res);
+    }
+}
+
+// The digest failed with a transient error is sent again by the retry policy of messages, after the last attempt
+// or a permanent error its messages are recorded as undeliverable
+fn handle_digest_result(backend: &mut Backend, ctx: &mut Context, digest: &Digest, res: DeliveryResult) {
+    let (status, smtp_code, message_id, error) = match res {
+        DeliveryResult::Sent {
+            smtp_code,
+            message_id,
+            ..
+        } => {
+            info!("digest sent to {}, messages = {}", digest.to.email, digest.items.len());
+            for item in digest.items.iter() {
+                if let Some(update_counter) = item.update_counter {
+                    ctx.sent.record(&item.uri, update_counter);
+                }
+            }
+            ctx.digest.remove(digest);
+            (DeliveryStatus::Sent, smtp_code, message_id, None)
+        },
+        DeliveryResult::TransientError {
+            smtp_code,
+            text,
+        } => match ctx.retry_queue.next_attempt(digest.attempt) {
+            Some(next_attempt) => {
+                ctx.digest.schedule(digest, next_attempt, &text);
+                (DeliveryStatus::Retrying, smtp_code, None, Some(text))
+            },
+            None => {
+                for item in digest.items.iter() {
+                    ctx.undeliverable.push(&item.uri, digest.attempt, &text);
+                }
+                ctx.digest.remove(digest);
+                (DeliveryStatus::Failed, smtp_code, None, Some(text))
+            },
+        },
+        DeliveryResult::PermanentError {
+            smtp_code,
+            text,
+        } => {
+            for item in digest.items.iter() {
+                ctx.undeliverable.push(&item.uri, digest.attempt, &text);
+            }
+            ctx.digest.remove(digest);
+            (DeliveryStatus::Failed, smtp_code, None, Some(text))
+        },
+        DeliveryResult::Ignored | DeliveryResult::Digested | DeliveryResult::Skipped => return,
+    };
+
+    for item in digest.items.iter() {
+        let report = DeliveryReport {
+            status: status.clone(),
+            attempt: digest.attempt,
+            smtp_code,
+            message_id: message_id.clone(),
+            error: error.clone(),
+            linked_attachments: vec![],
+        };
+        write_status(backend, ctx, &item.uri, report);
+    }
+}
+
+// Sends again the messages whose retry time has come, the current state of the message individual is sent
+fn retry_due(backend: &mut Backend, ctx: &mut Context) {
+    for entry in ctx.retry_queue.take_due() {
+        let mut msg_indv = Individual::default();
+        if backend.storage.get_individual(&entry.uri, &mut msg_indv) != ResultCode::Ok {
+            warn!("message for retry not found, uri = {}", entry.uri);
+            continue;
+        }
+        msg_indv.parse_all();
+
+        info!("retry sending, uri = {}, attempt = {}", entry.uri, entry.attempt + 1);
+        let update_counter = msg_indv.get_first_integer("v-s:updateCounter");
+        let res = prepare_deliverable(&mut msg_indv, backend, ctx);
+        handle_delivery_result(backend, ctx, &entry.uri, update_counter, entry.attempt + 1, res);
+    }
+}
+
+// Transient failures go to the retry queue, permanent failures and failures after the last attempt are recorded as undeliverable.
+// The result is written to the message individual, the version of message sent by the transport is recorded in the sent registry,
+// the digested message is recorded when its digest is sent
+fn handle_delivery_result(backend: &mut Backend, ctx: &mut Context, uri: &str, update_counter: Option<i64>, attempt: i64, res: DeliveryResult) {
+    if let Some(update_counter) = update_counter {
+        if matches!(res, DeliveryResult::Sent { .. }) {
+            ctx.sent.record(uri, update_counter);
+        }
+    }
+
+    let report = match res {
+        DeliveryResult::Ignored => return,
+        DeliveryResult::Digested => DeliveryReport {
+            status: DeliveryStatus::Digest,
+            attempt,
+            smtp_code: None,
+            message_id: None,
+            error: None,
+            linked_attachments: vec![],
+        },
+        DeliveryResult::Skipped => DeliveryReport {
+            status: DeliveryStatus::Skipped,
+            attempt,
+            smtp_code: None,
+            message_id: None,
+            error: None,
+            linked_attachments: vec![],
+        },
+        DeliveryResult::Sent {
+            smtp_code,
+            message_id,
+            linked_attachments,
+        } => DeliveryReport {
+            status: DeliveryStatus::Sent,
+            attempt,
+            smtp_code,
+            message_id,
+            error: None,
+            linked_attachments,
+        },
+        DeliveryResult::TransientError {
+            smtp_code,
+            text,
+        } => {
+            let status = if ctx.retry_queue.schedule(uri, attempt, &text) {
+                DeliveryStatus::Retrying
+            } else {
+                ctx.undeliverable.push(uri, attempt, &text);
+                DeliveryStatus::Failed
+            };
+            DeliveryReport {
+                status,
+                attempt,
+                smtp_code,
+                message_id: None,
+                error: Some(text),
+                linked_attachments: vec![],
+            }
+        },
+        DeliveryResult::PermanentError {
+            smtp_code,
+            text,
+        } => {
+            ctx.undeliverable.push(uri, attempt, &text);
+            DeliveryReport {
+                status: DeliveryStatus::Failed,
+                attempt,
+                smtp_code,
+                message_id: None,
+                error: Some(text),
+                linked_attachments: vec![],
+            }
+        },
+    };
+    write_status(backend, ctx, uri, report);
+}
+
+fn smtp_code(code: Code) -> i64 {
+    code.to_string().parse::<i64>().unwrap_or_default()
+}
+
 fn before_batch(_module: &mut Backend, _ctx: &mut Context, _size_batch: u32) -> Option<u32> {
     None
 }
@@ -105,6 +399,10 @@ fn prepare(backend: &mut Backend, ctx: &mut Context, queue_element: &mut Individ
         error!("failed to write module_info, op_id = {}, err = {:?}", op_id, e)
     }
 
+    if is_status_update(&mut new_state, &mut prev_state) {
+        return Ok(true);
+    }
+
     if let Some(types) = new_state.get_literals("rdf:type") {
         let is_version = types.contains(&"v-s:Version".to_owned());
         if is_version {
@@ -114,7 +412,17 @@ fn prepare(backend: &mut Backend, ctx: &mut Context, queue_element: &mut Individ
 
         for itype in types {
             if ctx.onto.is_some_entered(&itype, &["v-s:Deliverable"]) {
-                prepare_deliverable(&mut new_state, backend, ctx);
+                // The version of message which was sent already is not sent again when the queue is replayed, unless the module runs with --force_delivery
+                let update_counter = new_state.get_first_integer("v-s:updateCounter");
+                if let Some(counter) = update_counter {
+                    if ctx.sent.is_sent(new_state.get_id(), counter) && !ctx.force_delivery {
+                        info!("message {} was already sent, update counter = {}, ignore", new_state.get_id(), counter);
+                        break;
+                    }
+                }
+
+                let res = prepare_deliverable(&mut new_state, backend, ctx);
+                handle_delivery_result(backend, ctx, new_state.get_id(), update_counter, 1, res);
                 break;
             }
         }
@@ -127,7 +435,9 @@ struct IndvAddreses {
     has_message_type: Option<String>,
     from: String,
     to: Vec<String>,
-    //reply_to: Option<Vec<String>>,
+    reply_to: Vec<String>,
+    cc: Vec<String>,
+    bcc: Vec<String>,
     sender_mailbox: String,
     recipient_mailbox: Option<Vec<String>>,
 }
@@ -145,17 +455,35 @@ impl IndvAddreses {
             has_message_type: prepared_indv.get_first_literal("v-s:hasMessageType"),
             from,
             to: prepared_indv.get_literals("v-wf:to").unwrap_or_default(),
-            //reply_to: prepared_indv.get_literals("v-wf:replyTo"),
+            reply_to: prepared_indv.get_literals("v-wf:replyTo").unwrap_or_default(),
+            cc: prepared_indv.get_literals("v-wf:cc").unwrap_or_default(),
+            bcc: prepared_indv.get_literals("v-wf:bcc").unwrap_or_default(),
             sender_mailbox,
             recipient_mailbox: prepared_indv.get_literals("v-s:recipientMailbox"),
         }
     }
 }
 
+// Recipients whose preferences changed the delivery of message
+#[derive(Default)]
+struct Preferred {
+    // Mailboxes of the recipients who receive the message in the digest
+    digest: Vec<Mailbox>,
+    // Count of the recipients who rejected messages of the type
+    declined: usize,
+    // The digest preference is applied, it is not set for the sender and reply-to
+    collect_digest: bool,
+}
+
 struct MailAddreses {
     email_from: Mailbox,
     rr_email_to_hash: HashMap<String, Mailbox>,
-    //rr_reply_to_hash: HashMap<String, Mailbox>
+    // Recipients who receive the message in the digest
+    rr_digest_hash: HashMap<String, Mailbox>,
+    rr_reply_to_hash: HashMap<String, Mailbox>,
+    rr_cc_hash: HashMap<String, Mailbox>,
+    rr_bcc_hash: HashMap<String, Mailbox>,
+    declined: usize,
 }
 
 impl MailAddreses {
@@ -164,7 +492,7 @@ impl MailAddreses {
         let email_from = if ctx.always_use_mail_sender && !ctx.default_mail_sender.is_empty() && ctx.default_mail_sender.len() > 5 {
             info!("use default mail sender: {}", ctx.default_mail_sender);
             if !ctx.default_mail_sender.contains('@') {
-                extract_email(&None, &ctx.default_mail_sender.to_string(), ctx, backend).pop()
+                extract_email(&None, &ctx.default_mail_sender.to_string(), ctx, backend, &mut Preferred::default()).pop()
             } else {
                 Address::from_str(&ctx.default_mail_sender).ok().map(|address| Mailbox::new(Some("Veda System".to_string()), address))
             }
@@ -174,12 +502,12 @@ impl MailAddreses {
             // Пробуем from
             if !message_info.from.is_empty() {
                 info!("extract from: {}", message_info.from);
-                result = extract_email(&None, &message_info.from, ctx, backend).pop();
+                result = extract_email(&None, &message_info.from, ctx, backend, &mut Preferred::default()).pop();
             }
 
             // Если не получилось, пробуем default_mail_sender
             if result.is_none() && !ctx.default_mail_sender.is_empty() {
-                result = extract_email(&None, &ctx.default_mail_sender.to_string(), ctx, backend).pop();
+                result = extract_email(&None, &ctx.default_mail_sender.to_string(), ctx, backend, &mut Preferred::default()).pop();
             }
 
             // Если всё ещё нет, пробуем sender_mailbox
@@ -199,12 +527,11 @@ impl MailAddreses {
             },
         };
 
-        let mut rr_email_to_hash = HashMap::new();
-        for elt in message_info.to {
-            for r in extract_email(&message_info.has_message_type, &elt, ctx, backend) {
-                rr_email_to_hash.insert(r.email.to_string(), r);
-            }
-        }
+        let mut preferred = Preferred {
+            collect_digest: true,
+            ..Default::default()
+        };
+        let mut rr_email_to_hash = resolve_recipients(&message_info.has_message_type, &message_info.to, ctx, backend, &mut preferred);
 
         for el in message_info.recipient_mailbox.unwrap_or_default() {
             if let Ok(address) = Address::from_str(&el) {
@@ -212,14 +539,66 @@ impl MailAddreses {
             }
         }
 
+        // Every recipient is kept only in the first of to, cc and bcc
+        let mut rr_cc_hash = resolve_recipients(&message_info.has_message_type, &message_info.cc, ctx, backend, &mut preferred);
+        rr_cc_hash.retain(|email, _| !rr_email_to_hash.contains_key(email));
+        let mut rr_bcc_hash = resolve_recipients(&message_info.has_message_type, &message_info.bcc, ctx, backend, &mut preferred);
+        rr_bcc_hash.retain(|email, _| !rr_email_to_hash.contains_key(email) && !rr_cc_hash.contains_key(email));
+
+        let rr_reply_to_hash = resolve_recipients(&None, &message_info.reply_to, ctx, backend, &mut Preferred::default());
+
+        // The recipient of digest gets the message immediately if it is also addressed to them in another way
+        let mut rr_digest_hash = HashMap::new();
+        for r in preferred.digest {
+            let email = r.email.to_string();
+            if !rr_email_to_hash.contains_key(&email) && !rr_cc_hash.contains_key(&email) && !rr_bcc_hash.contains_key(&email) {
+                rr_digest_hash.insert(email, r);
+            }
+        }
+
         Some(MailAddreses {
             email_from,
             rr_email_to_hash,
+            rr_digest_hash,
+            rr_reply_to_hash,
+            rr_cc_hash,
+            rr_bcc_hash,
+            declined: preferred.declined,
         })
     }
+
+    fn has_recipients(&self) -> bool {
+        !self.rr_email_to_hash.is_empty() || !self.rr_cc_hash.is_empty() || !self.rr_bcc_hash.is_empty()
+    }
+}
+
+// The recipient is an appointment, position, person or mailbox as is
+fn resolve_recipients(
+    has_message_type: &Option<String>,
+    elts: &[String],
+    ctx: &mut Context,
+    backend: &mut Backend,
+    preferred: &mut Preferred,
+) -> HashMap<String, Mailbox> {
+    let mut res = HashMap::new();
+    for elt in elts {
+        if elt.contains('@') {
+            match Address::from_str(elt) {
+                Ok(address) => {
+                    res.insert(address.to_string(), Mailbox::new(None, address));
+                },
+                Err(e) => error!("invalid email format: {}, err = {}", elt, e),
+            }
+            continue;
+        }
+        for r in extract_email(has_message_type, elt, ctx, backend, preferred) {
+            res.insert(r.email.to_string(), r);
+        }
+    }
+    res
 }
 
-fn prepare_deliverable(msg_indv: &mut Individual, backend: &mut Backend, ctx: &mut Context) -> ResultCode {
+fn prepare_deliverable(msg_indv: &mut Individual, backend: &mut Backend, ctx: &mut Context) -> DeliveryResult {
     // Проверяем нужно ли подробное логирование
     let debug_logging = msg_indv.is_exists("v-s:debugEmail");
 
@@ -232,7 +611,7 @@ fn prepare_deliverable(msg_indv: &mut Individual, backend: &mut Backend, ctx: &m
         if debug_logging {
             info!("Individual {} is deleted, ignore", msg_indv.get_id());
         }
-        return ResultCode::Ok;
+        return DeliveryResult::Ignored;
     }
 
     let is_draft_of = msg_indv.get_first_literal("v-s:is_draft_of");
@@ -240,11 +619,10 @@ fn prepare_deliverable(msg_indv: &mut Individual, backend: &mut Backend, ctx: &m
         if debug_logging {
             info!("Individual {} is draft, ignore", msg_indv.get_id());
         }
-        return ResultCode::Ok;
+        return DeliveryResult::Ignored;
     }
 
-    let subject = msg_indv.get_first_literal("v-s:subject");
-    let message_body = msg_indv.get_first_literal("v-s:messageBody");
+    let (subject, mut message_body) = render_message(msg_indv, backend);
     let attachments = msg_indv.get_literals("v-s:attachment");
 
     if debug_logging {
@@ -273,18 +651,43 @@ fn prepare_deliverable(msg_indv: &mut Individual, backend: &mut Backend, ctx: &m
     let mail_addreses = match MailAddreses::from_indv_addreses(message_info, backend, ctx) {
         Some(addresses) => {
             if debug_logging {
-                info!("Resolved email addresses for {}: from={}, to_count={}", msg_indv.get_id(), addresses.email_from.email, addresses.rr_email_to_hash.len());
+                info!(
+                    "Resolved email addresses for {}: from={}, to_count={}, cc_count={}, bcc_count={}",
+                    msg_indv.get_id(),
+                    addresses.email_from.email,
+                    addresses.rr_email_to_hash.len(),
+                    addresses.rr_cc_hash.len(),
+                    addresses.rr_bcc_hash.len()
+                );
             }
             addresses
         },
         None => {
             error!("Failed to create email - no valid sender address found, uri = {}", msg_indv.get_id());
-            return ResultCode::InvalidIdentifier;
+            return DeliveryResult::PermanentError {
+                smtp_code: None,
+                text: "no valid sender address found".to_owned(),
+            };
         },
     };
 
-    if !mail_addreses.rr_email_to_hash.is_empty() {
-        let mut message_builder = Message::builder().from(mail_addreses.email_from.clone()).header(header::ContentTransferEncoding::QuotedPrintable);
+    if !mail_addreses.rr_digest_hash.is_empty() {
+        for to in mail_addreses.rr_digest_hash.values() {
+            if !ctx.digest.add(to, &mail_addreses.email_from, msg_indv.get_id(), msg_indv.get_first_integer("v-s:updateCounter"), &subject, &message_body) {
+                return DeliveryResult::TransientError {
+                    smtp_code: None,
+                    text: "failed to collect message for digest".to_owned(),
+                };
+            }
+        }
+        if !mail_addreses.has_recipients() {
+            return DeliveryResult::Digested;
+        }
+    }
+
+    if mail_addreses.has_recipients() {
+        // The transfer encoding is set on every single part, a multipart message must not have it
+        let mut message_builder = Message::builder().from(mail_addreses.email_from.clone());
 
         for el in mail_addreses.rr_email_to_hash.values() {
             message_builder = message_builder.to(el.clone());
@@ -292,117 +695,161 @@ fn prepare_deliverable(msg_indv: &mut Individual, backend: &mut Backend, ctx: &m
                 info!("Adding recipient for {}: {}", msg_indv.get_id(), el.email);
             }
         }
+        for el in mail_addreses.rr_cc_hash.values() {
+            message_builder = message_builder.cc(el.clone());
+            if debug_logging {
+                info!("Adding cc recipient for {}: {}", msg_indv.get_id(), el.email);
+            }
+        }
+        for el in mail_addreses.rr_bcc_hash.values() {
+            message_builder = message_builder.bcc(el.clone());
+            if debug_logging {
+                info!("Adding bcc recipient for {}: {}", msg_indv.get_id(), el.email);
+            }
+        }
+        for el in mail_addreses.rr_reply_to_hash.values() {
+            message_builder = message_builder.reply_to(el.clone());
+        }
 
         if let Some(s) = subject.clone() {
             message_builder = message_builder.subject(s);
         }
 
-        // Собираем сообщение в зависимости от наличия вложений
-        let email = if attachments.is_some() {
+        let is_html = message_body.as_ref().map_or(false, |body| body.to_lowercase().contains("<html>"));
+
+        // Читаем вложения, изображения на которые ссылается html как cid:<uri или имя файла> встраиваются в html
+        let mut attachment_parts = vec![];
+        let mut inline_parts = vec![];
+        let mut linked_attachments = vec![];
+        // Оценка размера письма, html отправляется вместе с текстовой версией
+        let mut message_size = message_body.as_ref().map_or(0, |body| {
+            if is_html {
+                body.len() as u64 * 2
+            } else {
+                body.len() as u64
+            }
+        });
+        if attachments.is_some() && debug_logging {
+            info!("Processing attachments for {}", msg_indv.get_id());
+        }
+        for id in attachments.clone().unwrap_or_default().iter() {
             if debug_logging {
-                info!("Processing attachments for {}", msg_indv.get_id());
+                info!("Processing attachment {} for {}", id, msg_indv.get_id());
             }
-            let mut builder = MultiPart::mixed().build();
-
-            // Добавляем тело письма
-            if let Some(ref body) = message_body {
-                let is_html = body.to_lowercase().contains("<html>");
-                if debug_logging {
-                    info!(
-                        "Adding message body for {}, content type: {}",
-                        msg_indv.get_id(),
-                        if is_html {
-                            "text/html"
-                        } else {
-                            "text/plain"
+            if let Some(file_info) = backend.get_individual(id, &mut Individual::default()) {
+                if let (Some(path), Some(file_uri), Some(file_name)) =
+                    (file_info.get_first_literal("v-s:filePath"), file_info.get_first_literal("v-s:fileUri"), file_info.get_first_literal("v-s:fileName"))
+                {
+                    if !path.is_empty() {
+                        let full_path = format!("{}/{}/{}", ATTACHMENTS_DB_PATH, path, file_uri);
+
+                        // Файл сверх лимитов соединения заменяется ссылкой, base64 увеличивает размер на треть
+                        let size = std::fs::metadata(&full_path).map(|metadata| metadata.len()).unwrap_or_default();
+                        let encoded_size = size.div_ceil(3) * 4;
+                        if ctx.max_attachment_size.map_or(false, |max| size > max) || ctx.max_message_size.map_or(false, |max| message_size + encoded_size > max) {
+                            warn!("Attachment {} of {} is too large ({} bytes), it is sent as a link", id, msg_indv.get_id(), size);
+                            linked_attachments.push((id.to_owned(), file_name, size));
+                            continue;
                         }
-                    );
-                }
+                        message_size += encoded_size;
 
-                let body_part = if is_html {
-                    SinglePart::builder()
-                        .header(header::ContentType::parse("text/html; charset=utf-8").unwrap())
-                        .header(header::ContentTransferEncoding::QuotedPrintable)
-                        .body(body.clone())
-                } else {
-                    SinglePart::builder()
-                        .header(header::ContentType::parse("text/plain; charset=utf-8").unwrap())
-                        .header(header::ContentTransferEncoding::QuotedPrintable)
-                        .body(body.clone())
-                };
-                builder = builder.singlepart(body_part);
-            }
+                        if debug_logging {
+                            info!("Reading attachment from {}", full_path);
+                        }
 
-            // Добавляем вложения
-            for id in attachments.clone().unwrap().iter() {
-                if debug_logging {
-                    info!("Processing attachment {} for {}", id, msg_indv.get_id());
-                }
-                if let Some(file_info) = backend.get_individual(id, &mut Individual::default()) {
-                    if let (Some(path), Some(file_uri), Some(file_name)) =
-                        (file_info.get_first_literal("v-s:filePath"), file_info.get_first_literal("v-s:fileUri"), file_info.get_first_literal("v-s:fileName"))
-                    {
-                        if !path.is_empty() {
-                            let full_path = format!("{}/{}/{}", ATTACHMENTS_DB_PATH, path, file_uri);
-                            if debug_logging {
-                                info!("Reading attachment from {}", full_path);
-                            }
+                        match std::fs::read(&full_path) {
+                            Ok(content) => {
+                                let mime = mime_guess::from_path(&file_name).first_or_octet_stream();
+                                let mime_str = mime.essence_str().to_string();
+                                if debug_logging {
+                                    info!("Attachment {} mime type: {}", file_name, mime_str);
+                                }
 
-                            match std::fs::read(&full_path) {
-                                Ok(content) => {
-                                    let mime = mime_guess::from_path(&file_name).first_or_octet_stream();
-                                    let mime_str = mime.essence_str().to_string();
+                                let content_type = header::ContentType::parse(&mime_str).unwrap_or_else(|_| {
                                     if debug_logging {
-                                        info!("Attachment {} mime type: {}", file_name, mime_str);
+                                        warn!("Failed to parse mime type {}, using octet-stream", mime_str);
                                     }
+                                    header::ContentType::parse("application/octet-stream").unwrap()
+                                });
+
+                                let cid = match &message_body {
+                                    Some(body) if is_html && mime.type_() == mime_guess::mime::IMAGE => {
+                                        [id.as_str(), file_name.as_str()].into_iter().find(|cid| body.contains(&format!("cid:{}", cid)))
+                                    },
+                                    _ => None,
+                                };
 
-                                    let content_type = header::ContentType::parse(&mime_str).unwrap_or_else(|_| {
-                                        if debug_logging {
-                                            warn!("Failed to parse mime type {}, using octet-stream", mime_str);
-                                        }
-                                        header::ContentType::parse("application/octet-stream").unwrap()
-                                    });
-
+                                if let Some(cid) = cid {
+                                    inline_parts.push(Attachment::new_inline(cid.to_owned()).body(content, content_type));
+                                    if debug_logging {
+                                        info!("Successfully added inline image {}, cid = {}", file_name, cid);
+                                    }
+                                } else {
                                     let attachment = SinglePart::builder()
                                         .header(content_type)
                                         .header(header::ContentDisposition::attachment(&file_name))
                                         .header(header::ContentTransferEncoding::Base64)
                                         .body(content);
 
-                                    builder = builder.singlepart(attachment);
+                                    attachment_parts.push(attachment);
                                     if debug_logging {
                                         info!("Successfully added attachment {}", file_name);
                                     }
-                                },
-                                Err(e) => {
-                                    error!("Failed to read attachment {} for email {}, err = {:?}", &full_path, msg_indv.get_id(), e);
-                                },
-                            }
+                                }
+                            },
+                            Err(e) => {
+                                error!("Failed to read attachment {} for email {}, err = {:?}", &full_path, msg_indv.get_id(), e);
+                            },
                         }
                     }
                 }
             }
+        }
 
-            message_builder.multipart(builder)
-        } else if let Some(ref body) = message_body {
-            let is_html = body.to_lowercase().contains("<html>");
-            if debug_logging {
-                info!(
-                    "Creating single part message for {}, content type: {}",
-                    msg_indv.get_id(),
-                    if is_html {
-                        "text/html"
-                    } else {
-                        "text/plain"
-                    }
-                );
+        if !linked_attachments.is_empty() {
+            message_body = Some(add_attachment_links(message_body.take().unwrap_or_default(), is_html, &linked_attachments, &ctx.app_url));
+        }
+
+        if debug_logging && message_body.is_some() {
+            info!(
+                "Adding message body for {}, content type: {}, inline images: {}",
+                msg_indv.get_id(),
+                if is_html {
+                    "multipart/alternative"
+                } else {
+                    "text/plain"
+                },
+                inline_parts.len()
+            );
+        }
+
+        // Собираем сообщение в зависимости от наличия вложений
+        let email = if !attachment_parts.is_empty() {
+            let mut builder = MultiPart::mixed().build();
+
+            // Добавляем тело письма
+            if let Some(ref body) = message_body {
+                builder = if is_html {
+                    builder.multipart(html_body(body, inline_parts))
+                } else {
+                    builder.singlepart(
+                        SinglePart::builder()
+                            .header(header::ContentType::parse("text/plain; charset=utf-8").unwrap())
+                            .header(header::ContentTransferEncoding::QuotedPrintable)
+                            .body(body.clone()),
+                    )
+                };
             }
 
+            // Добавляем вложения
+            for attachment in attachment_parts {
+                builder = builder.singlepart(attachment);
+            }
+
+            message_builder.multipart(builder)
+        } else if let Some(ref body) = message_body {
             if is_html {
-                message_builder
-                    .header(header::ContentType::parse("text/html; charset=utf-8").unwrap())
-                    .header(header::ContentTransferEncoding::QuotedPrintable)
-                    .body(body.clone())
+                message_builder.multipart(html_body(body, inline_parts))
             } else {
                 message_builder
                     .header(header::ContentType::parse("text/plain; charset=utf-8").unwrap())
@@ -420,90 +867,17 @@ fn prepare_deliverable(msg_indv: &mut Individual, backend: &mut Backend, ctx: &m
         };
 
         match email {
-            Ok(email) => {
-                if let Some(mailer) = &ctx.smtp_client {
-                    if debug_logging {
-                        info!("Attempting to send email for {}", msg_indv.get_id());
-                    }
-                    match mailer.send(&email) {
-                        Ok(response) => {
-                            // Извлекаем message_id из ответа SMTP сервера
-                            let message_id = response.message().collect::<Vec<_>>().first().and_then(|msg| {
-                                if let Some(start) = msg.find('<') {
-                                    if let Some(end) = msg[start..].find('>') {
-                                        Some(&msg[start..start + end + 1])
-                                    } else {
-                                        None
-                                    }
-                                } else {
-                                    None
-                                }
-                            });
-
-                            // Базовый лог для любого случая
-                            info!(
-                                "Email sent: msg={}, from={}, to={:?}, smtp_code={}, message_id={}",
-                                msg_indv.get_id(),
-                                mail_addreses.email_from.email,
-                                mail_addreses.rr_email_to_hash.values().map(|m| m.email.to_string()).collect::<Vec<_>>(),
-                                response.code(),
-                                message_id.unwrap_or("unknown")
-                            );
-
-                            if debug_logging {
-                                info!("Full SMTP server response: {:?}", response);
-                            }
-                            return ResultCode::Ok;
-                        },
-                        Err(e) => {
-                            error!("Failed to send email, uri = {}, error details: {:?}", msg_indv.get_id(), e);
-
-                            // Определяем тип ошибки и выводим соответствующую информацию
-                            if e.is_permanent() {
-                                if let Some(code) = e.status() {
-                                    error!("Permanent SMTP error with code {}: {}", code, e);
-                                } else {
-                                    error!("Permanent SMTP error without code: {}", e);
-                                }
-                                error!("This error cannot be resolved by retrying");
-                            } else if e.is_transient() {
-                                if let Some(code) = e.status() {
-                                    error!("Transient SMTP error with code {}: {}", code, e);
-                                } else {
-                                    error!("Transient SMTP error without code: {}", e);
-                                }
-                                error!("This error might be resolved by retrying later");
-                            } else if e.is_client() {
-                                error!("SMTP client error: {}", e);
-                            } else if e.is_response() {
-                                error!("SMTP response error: {}", e);
-                            } else if e.is_timeout() {
-                                error!("SMTP timeout error: {}", e);
-                            }
-
-                            // Выводим информацию об исходной ошибке, если она есть
-                            if let Some(source) = e.source() {
-                                error!("Underlying error: {}", source);
-                                if let Some(io_err) = source.downcast_ref::<std::io::Error>() {
-                                    error!("IO error kind: {:?}", io_err.kind());
-                                    if let Some(os_err) = io_err.raw_os_error() {
-                                        error!("OS error code: {}", os_err);
-                                    }
-                                }
-                            }
-
-                            // Логируем контекст отправки
-                            error!(
-                                "Email context: from={}, to={:?}, subject={:?}",
-                                mail_addreses.email_from.email,
-                                mail_addreses.rr_email_to_hash.values().map(|m| m.email.to_string()).collect::<Vec<_>>(),
-                                subject
-                            );
-                        },
-                    }
-                } else {
-                    error!("Failed to send email, mailer not found, uri = {}", msg_indv.get_id());
-                }
+            Ok(email) => match send_email(ctx, email, msg_indv.get_id(), &subject, debug_logging) {
+                DeliveryResult::Sent {
+                    smtp_code,
+                    message_id,
+                    ..
+                } => DeliveryResult::Sent {
+                    smtp_code,
+                    message_id,
+                    linked_attachments: linked_attachments.into_iter().map(|(id, _, _)| id).collect(),
+                },
+                res => res,
             },
             Err(e) => {
                 error!("Failed to build email, id = {}, error: {}", msg_indv.get_id(), e);
@@ -515,16 +889,207 @@ fn prepare_deliverable(msg_indv: &mut Individual, backend: &mut Backend, ctx: &m
                     message_body.is_some(),
                     attachments.is_some()
                 );
+                DeliveryResult::PermanentError {
+                    smtp_code: None,
+                    text: format!("failed to build email: {}", e),
+                }
             },
         }
+    } else if mail_addreses.declined > 0 {
+        info!("Recipients of {} declined messages of the type, skip", msg_indv.get_id());
+        DeliveryResult::Skipped
     } else {
         error!("No valid recipients found for {}", msg_indv.get_id());
+        DeliveryResult::PermanentError {
+            smtp_code: None,
+            text: "no valid recipients found".to_owned(),
+        }
     }
+}
+
+// The list of links to the attachments which were not attached, [attachments] are (uri, file name, size)
+fn add_attachment_links(mut body: String, is_html: bool, attachments: &[(String, String, u64)], app_url: &str) -> String {
+    let link = |uri: &str| format!("{}/{}", app_url.trim_end_matches('/'), uri);
+
+    if is_html {
+        let mut links = String::from("<p>Files exceeding the size limit are available by links:</p><ul>");
+        for (uri, file_name, size) in attachments {
+            links.push_str(&format!("<li><a href=\"{}\">{}</a> ({} KB)</li>", escape_html(&link(uri)), escape_html(file_name), size.div_ceil(1024)));
+        }
+        links.push_str("</ul>");
+
+        match body.to_ascii_lowercase().rfind("</body>") {
+            Some(pos) => body.insert_str(pos, &links),
+            None => body.push_str(&links),
+        }
+        body
+    } else {
+        if !body.is_empty() {
+            body.push_str("\n\n");
+        }
+        body.push_str("Files exceeding the size limit are available by links:\n");
+        for (uri, file_name, size) in attachments {
+            body.push_str(&format!("{} ({} KB): {}\n", file_name, size.div_ceil(1024), link(uri)));
+        }
+        body
+    }
+}
+
+// Html is sent together with its plain text version, the inline images are put next to html into multipart/related
+fn html_body(html: &str, inline_parts: Vec<SinglePart>) -> MultiPart {
+    let text_part = SinglePart::builder()
+        .header(header::ContentType::parse("text/plain; charset=utf-8").unwrap())
+        .header(header::ContentTransferEncoding::QuotedPrintable)
+        .body(html_to_text(html));
+    let html_part = SinglePart::builder()
+        .header(header::ContentType::parse("text/html; charset=utf-8").unwrap())
+        .header(header::ContentTransferEncoding::QuotedPrintable)
+        .body(html.to_owned());
+
+    if inline_parts.is_empty() {
+        return MultiPart::alternative().singlepart(text_part).singlepart(html_part);
+    }
+    let mut related = MultiPart::related().singlepart(html_part);
+    for part in inline_parts {
+        related = related.singlepart(part);
+    }
+    MultiPart::alternative().singlepart(text_part).multipart(related)
+}
+
+// Signs the built message if dkim is configured and sends it by the configured transport
+fn send_email(ctx: &Context, mut email: Message, uri: &str, subject: &Option<String>, debug_logging: bool) -> DeliveryResult {
+    if let Some(dkim) = &ctx.dkim {
+        email.sign(dkim);
+        if debug_logging {
+            info!("Email {} is signed by dkim", uri);
+        }
+    }
+    let envelope_from = email.envelope().from().map(|a| a.to_string()).unwrap_or_default();
+    let envelope_to = email.envelope().to().iter().map(|a| a.to_string()).collect::<Vec<_>>();
+
+    if let Some(MailTransport::Local(local)) = &ctx.transport {
+        return match local.send(&email, uri) {
+            Ok(path) => {
+                info!("Email written: msg={}, from={}, path={}", uri, envelope_from, path);
+                DeliveryResult::Sent {
+                    smtp_code: None,
+                    message_id: None,
+                    linked_attachments: vec![],
+                }
+            },
+            Err(e) => {
+                error!("Failed to write email, uri = {}, err = {:?}", uri, e);
+                DeliveryResult::TransientError {
+                    smtp_code: None,
+                    text: e.to_string(),
+                }
+            },
+        };
+    }
+
+    if let Some(MailTransport::Smtp(mailer)) = &ctx.transport {
+        if debug_logging {
+            info!("Attempting to send email for {}", uri);
+        }
+        match mailer.send(&email) {
+            Ok(response) => {
+                // Извлекаем message_id из ответа SMTP сервера
+                let message_id = response.message().collect::<Vec<_>>().first().and_then(|msg| {
+                    if let Some(start) = msg.find('<') {
+                        if let Some(end) = msg[start..].find('>') {
+                            Some(&msg[start..start + end + 1])
+                        } else {
+                            None
+                        }
+                    } else {
+                        None
+                    }
+                });
+
+                // Базовый лог для любого случая
+                info!(
+                    "Email sent: msg={}, from={}, to={:?}, smtp_code={}, message_id={}",
+                    uri,
+                    envelope_from,
+                    envelope_to,
+                    response.code(),
+                    message_id.unwrap_or("unknown")
+                );
+
+                if debug_logging {
+                    info!("Full SMTP server response: {:?}", response);
+                }
+                DeliveryResult::Sent {
+                    smtp_code: Some(smtp_code(response.code())),
+                    message_id: message_id.map(|id| id.to_owned()),
+                    linked_attachments: vec![],
+                }
+            },
+            Err(e) => {
+                error!("Failed to send email, uri = {}, error details: {:?}", uri, e);
+
+                // Определяем тип ошибки и выводим соответствующую информацию
+                if e.is_permanent() {
+                    if let Some(code) = e.status() {
+                        error!("Permanent SMTP error with code {}: {}", code, e);
+                    } else {
+                        error!("Permanent SMTP error without code: {}", e);
+                    }
+                    error!("This error cannot be resolved by retrying");
+                } else if e.is_transient() {
+                    if let Some(code) = e.status() {
+                        error!("Transient SMTP error with code {}: {}", code, e);
+                    } else {
+                        error!("Transient SMTP error without code: {}", e);
+                    }
+                    error!("This error might be resolved by retrying later");
+                } else if e.is_client() {
+                    error!("SMTP client error: {}", e);
+                } else if e.is_response() {
+                    error!("SMTP response error: {}", e);
+                } else if e.is_timeout() {
+                    error!("SMTP timeout error: {}", e);
+                }
+
+                // Выводим информацию об исходной ошибке, если она есть
+                if let Some(source) = e.source() {
+                    error!("Underlying error: {}", source);
+                    if let Some(io_err) = source.downcast_ref::<std::io::Error>() {
+                        error!("IO error kind: {:?}", io_err.kind());
+                        if let Some(os_err) = io_err.raw_os_error() {
+                            error!("OS error code: {}", os_err);
+                        }
+                    }
+                }
 
-    ResultCode::InternalServerError
+                // Логируем контекст отправки
+                error!("Email context: from={}, to={:?}, subject={:?}", envelope_from, envelope_to, subject);
+
+                // Only the rejection by server is final, timeouts and connection errors are transient
+                if e.is_permanent() {
+                    DeliveryResult::PermanentError {
+                        smtp_code: e.status().map(smtp_code),
+                        text: e.to_string(),
+                    }
+                } else {
+                    DeliveryResult::TransientError {
+                        smtp_code: e.status().map(smtp_code),
+                        text: e.to_string(),
+                    }
+                }
+            },
+        }
+    } else {
+        error!("Failed to send email, mailer not found, uri = {}", uri);
+        DeliveryResult::PermanentError {
+            smtp_code: None,
+            text: "mailer not found".to_owned(),
+        }
+    }
 }
 
-fn get_emails_from_appointment(has_message_type: &Option<String>, ap: &mut Individual, backend: &mut Backend) -> Vec<Mailbox> {
+// The mailboxes of the person who receives notifications as a digest are added to [preferred] instead of result
+fn get_emails_from_appointment(has_message_type: &Option<String>, ap: &mut Individual, backend: &mut Backend, preferred: &mut Preferred) -> Vec<Mailbox> {
     if ap.any_exists("v-s:hasDelegationPurpose", &["d:delegate_Control"]) {
         return vec![];
     }
@@ -547,34 +1112,13 @@ fn get_emails_from_appointment(has_message_type: &Option<String>, ap: &mut Indiv
 
     let label = ap.get_first_literal("rdfs:label").unwrap_or_default();
 
-    if let Some(has_message_type) = has_message_type {
-        if let Some(preference_uri) = prs.get_first_literal("v-ui:hasPreferences") {
-            if let Some(preference) = backend.get_individual(&preference_uri, &mut Individual::default()) {
-                info!("found preferences, uri = {}, has message type = {}", p_uri, has_message_type);
-
-                let mut need_send = true;
-                if let Some(receive_message_types) = preference.get_literals("v-ui:rejectMessageType") {
-                    for msg_type in receive_message_types.iter() {
-                        info!("check preferences {}", msg_type);
-                        if !has_message_type.is_empty() && msg_type == has_message_type {
-                            need_send = false;
-                            break;
-                        }
-
-                        if !has_message_type.is_empty() && msg_type == "v-s:OtherNotification" {
-                            need_send = false;
-                            break;
-                        }
-                    }
-                }
-
-                if !need_send {
-                    info!("declined to send message");
-                    return vec![];
-                }
-            }
-        }
-    }
+    let to_digest = match read_preferences(has_message_type, &p_uri, &mut prs, preferred.collect_digest, backend) {
+        Some(to_digest) => to_digest,
+        None => {
+            preferred.declined += 1;
+            return vec![];
+        },
+    };
 
     let ac_uri = prs.get_first_literal("v-s:hasAccount");
     if ac_uri.is_none() {
@@ -600,16 +1144,73 @@ fn get_emails_from_appointment(has_message_type: &Option<String>, ap: &mut Indiv
                 error!("invalid email format: {}", el);
             }
         }
+
+        if to_digest {
+            preferred.digest.append(&mut res);
+        }
         res
     } else {
         vec![]
     }
 }
 
-fn extract_email(has_message_type: &Option<String>, ap_id: &str, ctx: &mut Context, backend: &mut Backend) -> Vec<Mailbox> {
+// Returns None if the person rejected messages of the type, otherwise true if the person receives them in the digest
+fn read_preferences(has_message_type: &Option<String>, p_uri: &str, prs: &mut Individual, collect_digest: bool, backend: &mut Backend) -> Option<bool> {
+    let preference_uri = match prs.get_first_literal("v-ui:hasPreferences") {
+        Some(preference_uri) => preference_uri,
+        None => return Some(false),
+    };
+    let mut preference_indv = Individual::default();
+    let preference = match backend.get_individual(&preference_uri, &mut preference_indv) {
+        Some(preference) => preference,
+        None => return Some(false),
+    };
+
+    // The rejected types are checked for the messages with type, the digest is applied to every message
+    if let Some(has_message_type) = has_message_type {
+        info!("found preferences, uri = {}, has message type = {}", p_uri, has_message_type);
+
+        let mut need_send = true;
+        if let Some(receive_message_types) = preference.get_literals("v-ui:rejectMessageType") {
+            for msg_type in receive_message_types.iter() {
+                info!("check preferences {}", msg_type);
+                if !has_message_type.is_empty() && msg_type == has_message_type {
+                    need_send = false;
+                    break;
+                }
+
+                if !has_message_type.is_empty() && msg_type == "v-s:OtherNotification" {
+                    need_send = false;
+                    break;
+                }
+            }
+        }
+
+        if !need_send {
+            info!("declined to send message");
+            return None;
+        }
+    }
+
+    Some(collect_digest && preference.is_exists_bool("v-ui:receiveDigest", true))
+}
+
+fn extract_email(has_message_type: &Option<String>, ap_id: &str, ctx: &mut Context, backend: &mut Backend, preferred: &mut Preferred) -> Vec<Mailbox> {
+    extract_email_recursive(has_message_type, ap_id, ctx, backend, preferred, &mut HashSet::new())
+}
+
+// Groups and organization units are expanded to their members, [visited] protects from the cycles of membership
+fn extract_email_recursive(
+    has_message_type: &Option<String>,
+    ap_id: &str,
+    ctx: &mut Context,
+    backend: &mut Backend,
+    preferred: &mut Preferred,
+    visited: &mut HashSet<String>,
+) -> Vec<Mailbox> {
     let mut res = Vec::new();
 
-    if ap_id.is_empty() {
+    if ap_id.is_empty() || !visited.insert(ap_id.to_owned()) {
         return vec![];
     }
 
@@ -617,7 +1218,7 @@ fn extract_email(has_message_type: &Option<String>, ap_id: &str, ctx: &mut Conte
         let label = indv.get_first_literal("rdfs:label").unwrap_or_default();
 
         if indv.any_exists("rdf:type", &["v-s:Appointment"]) {
-            return get_emails_from_appointment(has_message_type, indv, backend);
+            return get_emails_from_appointment(has_message_type, indv, backend, preferred);
         } else if indv.any_exists("rdf:type", &["v-s:Position"]) {
             let l_individuals = backend
                 .fts
@@ -626,11 +1227,18 @@ fn extract_email(has_message_type: &Option<String>, ap_id: &str, ctx: &mut Conte
             for id in l_individuals.result {
                 if let Some(individual) = backend.get_individual(&id, &mut Individual::default()) {
                     if !individual.is_exists_bool("v-s:deleted", true) {
-                        res.append(&mut get_emails_from_appointment(has_message_type, individual, backend));
+                        res.append(&mut get_emails_from_appointment(has_message_type, individual, backend, preferred));
                     }
                 }
             }
         } else if indv.any_exists("rdf:type", &["v-s:Person"]) {
+            let to_digest = match read_preferences(has_message_type, ap_id, indv, backend) {
+                Some(to_digest) => to_digest,
+                None => {
+                    preferred.declined += 1;
+                    return vec![];
+                },
+            };
             for ac_uri in indv.get_literals("v-s:hasAccount").unwrap_or_default() {
                 if ac_uri.is_empty() {
                     return vec![];
@@ -651,17 +1259,47 @@ fn extract_email(has_message_type: &Option<String>, ap_id: &str, ctx: &mut Conte
                                 error!("invalid email format: {}", el);
                             }
                         }
+                        if to_digest {
+                            preferred.digest.append(&mut res);
+                        }
                         return res;
                     }
                 }
             }
+        } else if is_instance_of(ctx, indv, "v-s:Group") {
+            // A member of group may be a group or an organization unit itself
+            let query = format!("'rdf:type' == 'v-s:Membership' && 'v-s:memberOf' == '{}'", indv.get_id());
+            for id in backend.fts.query(FTQuery::new_with_ticket(&ctx.sys_ticket, &query)).result {
+                let mut members = vec![];
+                if let Some(membership) = backend.get_individual(&id, &mut Individual::default()) {
+                    if !membership.is_exists_bool("v-s:deleted", true) {
+                        members = membership.get_literals("v-s:resource").unwrap_or_default();
+                    }
+                }
+                for member in members {
+                    res.append(&mut extract_email_recursive(has_message_type, &member, ctx, backend, preferred, visited));
+                }
+            }
+        } else if is_instance_of(ctx, indv, "v-s:OrganizationUnit") {
+            // Organizations, departments and groups of organization are expanded by their subunits, positions and appointments
+            let query = format!("'v-s:parentUnit' == '{}'", indv.get_id());
+            for id in backend.fts.query(FTQuery::new_with_ticket(&ctx.sys_ticket, &query)).result {
+                let is_deleted = backend.get_individual(&id, &mut Individual::default()).map_or(true, |unit| unit.is_exists_bool("v-s:deleted", true));
+                if !is_deleted {
+                    res.append(&mut extract_email_recursive(has_message_type, &id, ctx, backend, preferred, visited));
+                }
+            }
         } else {
-            error!("failed to extract email from {}, this is not an appointment, nor a position", ap_id);
+            error!("failed to extract email from {}, this is not an appointment, position, person, group or organization unit", ap_id);
         }
     }
     res
 }
 
+fn is_instance_of(ctx: &Context, indv: &mut Individual, class: &str) -> bool {
+    indv.get_literals("rdf:type").unwrap_or_default().iter().any(|itype| itype == class || ctx.onto.is_some_entered(itype, &[class]))
+}
+
 fn split_email_address(email: &str) -> Option<(String, String)> {
     let parts: Vec<&str> = email.split('@').collect();
     if parts.len() == 2 {
@@ -671,6 +1309,16 @@ fn split_email_address(email: &str) -> Option<(String, String)> {
     }
 }
 
+// Settings of the connection which do not depend on the transport: sender, dkim and size limits
+fn load_connection_settings(ctx: &mut Context, connection: &mut Individual) -> Result<(), &'static str> {
+    ctx.default_mail_sender = connection.get_first_literal("v-s:mailSender").unwrap_or_default();
+    ctx.always_use_mail_sender = connection.get_first_bool("v-s:alwaysUseMailSender").unwrap_or_default();
+    ctx.dkim = load_dkim_config(connection)?;
+    ctx.max_attachment_size = connection.get_first_integer("v-s:maxAttachmentSize").filter(|size| *size > 0).map(|size| size as u64);
+    ctx.max_message_size = connection.get_first_integer("v-s:maxMessageSize").filter(|size| *size > 0).map(|size| size as u64);
+    Ok(())
+}
+
 fn connect_to_smtp(ctx: &mut Context, module: &mut Backend) -> bool {
     if let Some(node) = module.get_individual("cfg:standart_node", &mut Individual::default()) {
         if let Some(v) = node.get_literals("v-s:send_an_email_individual_by_event") {
@@ -679,6 +1327,41 @@ fn connect_to_smtp(ctx: &mut Context, module: &mut Backend) -> bool {
 
                 if module.storage.get_individual(&el, &mut connection) == ResultCode::Ok && !connection.is_exists_bool("v-s:delete", true) {
                     if let Some(transport) = connection.get_first_literal("v-s:transport") {
+                        if transport != "file" && transport != "maildir" && transport != "smtp" {
+                            continue;
+                        }
+                        if load_connection_settings(ctx, &mut connection).is_err() {
+                            return false;
+                        }
+
+                        // Messages are written to the directory [point] instead of sending
+                        if transport == "file" || transport == "maildir" {
+                            info!("found connection configuration for local {} transport, uri = {}", transport, connection.get_id());
+
+                            let dir = connection.get_first_literal("v-s:point").unwrap_or_default();
+                            if dir.is_empty() {
+                                error!("parameter [point] is empty");
+                                return false;
+                            }
+
+                            let format = if transport == "maildir" {
+                                LocalFormat::Maildir
+                            } else {
+                                LocalFormat::File
+                            };
+                            return match LocalTransport::new(&dir, format) {
+                                Ok(local) => {
+                                    info!("messages are written to {}", dir);
+                                    ctx.transport = Some(MailTransport::Local(local));
+                                    true
+                                },
+                                Err(e) => {
+                                    error!("failed to create directory {} for messages, err = {:?}", dir, e);
+                                    false
+                                },
+                            };
+                        }
+
                         if transport == "smtp" {
                             info!("found connection configuration for smtp server, uri = {}", connection.get_id());
 
@@ -694,9 +1377,6 @@ fn connect_to_smtp(ctx: &mut Context, module: &mut Backend) -> bool {
                             let pass = connection.get_first_literal("v-s:password");
                             let use_smtp_utf8 = connection.get_first_bool("cfg:use_smtp_utf8").unwrap_or(true);
 
-                            ctx.default_mail_sender = connection.get_first_literal("v-s:mailSender").unwrap_or_default();
-                            ctx.always_use_mail_sender = connection.get_first_bool("v-s:alwaysUseMailSender").unwrap_or_default();
-
                             // Формируем URL для SMTP
                             let mut url = String::new();
 
@@ -773,7 +1453,7 @@ fn connect_to_smtp(ctx: &mut Context, module: &mut Backend) -> bool {
                                         .timeout(Some(Duration::from_secs(10))) // Таймаут подключения 10 секунд
                                         .build();
 
-                                    ctx.smtp_client = Some(transport);
+                                    ctx.transport = Some(MailTransport::Smtp(transport));
 
                                     if ctx.always_use_mail_sender {
                                         info!("use always_use_mail_sender parameter");
@@ -796,6 +1476,6 @@ fn connect_to_smtp(ctx: &mut Context, module: &mut Backend) -> bool {
         }
     }
 
-    error!("failed to find connection configuration for smtp server");
+    error!("failed to find connection configuration for smtp server or local transport");
     false
 }
@@ -0,0 +1,162 @@
+use chrono::Utc;
+use std::fs;
+use std::time::{Duration, Instant};
+use v_common::onto::datatype::Lang;
+use v_common::onto::individual::{Individual, RawObj};
+use v_common::onto::individual2msgpack::to_msgpack;
+use v_common::onto::parser::parse_raw;
+
+pub const RETRY_QUEUE_PATH: &str = "./data/email-retry";
+
+// How often the retry queue and the failed digests are checked for the messages to send again
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+// Message waiting for the next attempt to be sent
+pub struct RetryEntry {
+    pub uri: String,
+    pub attempt: i64,
+}
+
+// Persistent queue of the messages failed with a transient error, every message is kept in its own file until it is sent again,
+// the delay before the next attempt is doubled after every failure
+pub struct RetryQueue {
+    max_attempts: i64,
+    base_delay: i64,
+    max_delay: i64,
+    last_check: Option<Instant>,
+}
+
+impl RetryQueue {
+    pub fn new(max_attempts: i64, base_delay: i64, max_delay: i64) -> Result<Self, &'static str> {
+        if let Err(e) = fs::create_dir_all(RETRY_QUEUE_PATH) {
+            error!("failed to create retry queue {}, err = {:?}", RETRY_QUEUE_PATH, e);
+            return Err("failed to create retry queue");
+        }
+        Ok(RetryQueue {
+            max_attempts,
+            base_delay,
+            max_delay,
+            last_check: None,
+        })
+    }
+
+    // Returns the time of the attempt following [attempt], None if the attempts are exhausted
+    pub fn next_attempt(&self, attempt: i64) -> Option<i64> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        Some(Utc::now().timestamp() + retry_delay(self.base_delay, self.max_delay, attempt))
+    }
+
+    // Schedules the next attempt, returns false if the attempts are exhausted
+    pub fn schedule(&self, uri: &str, attempt: i64, err: &str) -> bool {
+        let next_attempt = match self.next_attempt(attempt) {
+            Some(next_attempt) => next_attempt,
+            None => return false,
+        };
+        let delay = next_attempt - Utc::now().timestamp();
+
+        let mut entry = Individual::default();
+        entry.set_id(uri);
+        entry.add_integer("attempt", attempt);
+        entry.add_datetime("next_attempt", next_attempt);
+        entry.add_string("error", err, Lang::none());
+
+        let mut raw: Vec<u8> = Vec::new();
+        if let Err(e) = to_msgpack(&entry, &mut raw) {
+            error!("failed to serialize retry entry, uri = {}, err = {:?}", uri, e);
+            return false;
+        }
+        if let Err(e) = fs::write(entry_path(uri), raw) {
+            error!("failed to write retry entry, uri = {}, err = {:?}", uri, e);
+            return false;
+        }
+        warn!("message is scheduled for retry, uri = {}, attempt = {}, delay = {}s, err = {}", uri, attempt, delay, err);
+        true
+    }
+
+    // Takes the entries whose time has come out of the queue, the queue is checked not more often than CHECK_INTERVAL
+    pub fn take_due(&mut self) -> Vec<RetryEntry> {
+        if let Some(last_check) = self.last_check {
+            if last_check.elapsed() < CHECK_INTERVAL {
+                return vec![];
+            }
+        }
+        self.last_check = Some(Instant::now());
+
+        let dir = match fs::read_dir(RETRY_QUEUE_PATH) {
+            Ok(dir) => dir,
+            Err(e) => {
+                error!("failed to read retry queue, err = {:?}", e);
+                return vec![];
+            },
+        };
+
+        let now = Utc::now().timestamp();
+        let mut due = vec![];
+        for file in dir.flatten() {
+            let path = file.path();
+            let raw = match fs::read(&path) {
+                Ok(raw) => raw,
+                Err(e) => {
+                    error!("failed to read retry entry {:?}, err = {:?}", path, e);
+                    continue;
+                },
+            };
+
+            let mut entry = Individual::new_raw(RawObj::new(raw));
+            if parse_raw(&mut entry).is_err() {
+                error!("failed to parse retry entry {:?}, remove it", path);
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            if entry.get_first_datetime("next_attempt").unwrap_or_default() > now {
+                continue;
+            }
+
+            if let Err(e) = fs::remove_file(&path) {
+                error!("failed to remove retry entry {:?}, err = {:?}", path, e);
+                continue;
+            }
+            due.push(RetryEntry {
+                uri: entry.get_id().to_string(),
+                attempt: entry.get_first_integer("attempt").unwrap_or(1),
+            });
+        }
+        due
+    }
+}
+
+// Delay before the attempt following [attempt]: base_delay after the first failure, doubled after every next one, not more than max_delay
+fn retry_delay(base_delay: i64, max_delay: i64, attempt: i64) -> i64 {
+    base_delay.saturating_mul(1 << (attempt - 1).clamp(0, 30)).min(max_delay)
+}
+
+fn entry_path(uri: &str) -> String {
+    format!("{}/{}", RETRY_QUEUE_PATH, urlencoding::encode(uri))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_doubles_after_every_failure() {
+        assert_eq!(retry_delay(60, 3600, 1), 60);
+        assert_eq!(retry_delay(60, 3600, 2), 120);
+        assert_eq!(retry_delay(60, 3600, 3), 240);
+        assert_eq!(retry_delay(60, 3600, 6), 1920);
+    }
+
+    #[test]
+    fn retry_delay_is_limited_by_max_delay() {
+        assert_eq!(retry_delay(60, 3600, 7), 3600);
+        assert_eq!(retry_delay(60, 3600, 100), 3600);
+        assert_eq!(retry_delay(i64::MAX / 2, i64::MAX, 40), i64::MAX);
+    }
+
+    #[test]
+    fn retry_delay_of_first_attempt_is_base_delay() {
+        assert_eq!(retry_delay(60, 3600, 0), 60);
+    }
+}
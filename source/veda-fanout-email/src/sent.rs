@@ -0,0 +1,56 @@
+use chrono::Utc;
+use std::fs;
+use v_common::onto::individual::{Individual, RawObj};
+use v_common::onto::individual2msgpack::to_msgpack;
+use v_common::onto::parser::parse_raw;
+
+pub const SENT_REGISTRY_PATH: &str = "./data/email-sent";
+
+// Persistent record of the sent messages, it protects from sending again when the queue is read again from an earlier position.
+// Every message has its own file with the last v-s:updateCounter which was sent
+pub struct SentRegistry {}
+
+impl SentRegistry {
+    pub fn new() -> Result<Self, &'static str> {
+        if let Err(e) = fs::create_dir_all(SENT_REGISTRY_PATH) {
+            error!("failed to create sent registry {}, err = {:?}", SENT_REGISTRY_PATH, e);
+            return Err("failed to create sent registry");
+        }
+        Ok(SentRegistry {})
+    }
+
+    // Returns true if this or a later version of the message was already sent
+    pub fn is_sent(&self, uri: &str, update_counter: i64) -> bool {
+        let raw = match fs::read(entry_path(uri)) {
+            Ok(raw) => raw,
+            Err(_) => return false,
+        };
+
+        let mut entry = Individual::new_raw(RawObj::new(raw));
+        if parse_raw(&mut entry).is_err() {
+            error!("failed to parse sent registry entry, uri = {}", uri);
+            return false;
+        }
+        entry.get_first_integer("update_counter").map_or(false, |sent_counter| sent_counter >= update_counter)
+    }
+
+    pub fn record(&self, uri: &str, update_counter: i64) {
+        let mut entry = Individual::default();
+        entry.set_id(uri);
+        entry.add_integer("update_counter", update_counter);
+        entry.add_datetime("date", Utc::now().timestamp());
+
+        let mut raw: Vec<u8> = Vec::new();
+        if let Err(e) = to_msgpack(&entry, &mut raw) {
+            error!("failed to serialize sent registry entry, uri = {}, err = {:?}", uri, e);
+            return;
+        }
+        if let Err(e) = fs::write(entry_path(uri), raw) {
+            error!("failed to write sent registry entry, uri = {}, err = {:?}", uri, e);
+        }
+    }
+}
+
+fn entry_path(uri: &str) -> String {
+    format!("{}/{}", SENT_REGISTRY_PATH, urlencoding::encode(uri))
+}
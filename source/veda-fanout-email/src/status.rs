@@ -0,0 +1,126 @@
+use crate::Context;
+use chrono::Utc;
+use v_common::module::veda_backend::Backend;
+use v_common::onto::datatype::Lang;
+use v_common::onto::individual::Individual;
+use v_common::v_api::api_client::IndvOp;
+use v_common::v_api::obj::ResultCode;
+
+#[derive(Clone)]
+pub enum DeliveryStatus {
+    Sent,
+    Retrying,
+    Failed,
+    // Waiting for the digest of recipient
+    Digest,
+    // Not sent, the recipients rejected messages of the type
+    Skipped,
+}
+
+impl DeliveryStatus {
+    fn as_uri(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Sent => "v-s:DeliveryStatusSent",
+            DeliveryStatus::Retrying => "v-s:DeliveryStatusRetrying",
+            DeliveryStatus::Failed => "v-s:DeliveryStatusFailed",
+            DeliveryStatus::Digest => "v-s:DeliveryStatusDigest",
+            DeliveryStatus::Skipped => "v-s:DeliveryStatusSkipped",
+        }
+    }
+}
+
+pub struct DeliveryReport {
+    pub status: DeliveryStatus,
+    pub attempt: i64,
+    pub smtp_code: Option<i64>,
+    pub message_id: Option<String>,
+    pub error: Option<String>,
+    pub linked_attachments: Vec<String>,
+}
+
+// Predicates of the delivery state which are written only by some results, they are removed when the next result has no value for them
+const OPTIONAL_STATUS_PREDICATES: [&str; 4] = ["v-s:smtpCode", "v-s:smtpMessageId", "v-s:deliveryError", "v-s:attachmentSentAsLink"];
+
+// Writes the delivery state to the message individual, the other predicates of the message are not changed.
+// The values left by the previous attempt are removed first, so the status of message is not contradictory
+pub fn write_status(backend: &mut Backend, ctx: &Context, uri: &str, report: DeliveryReport) {
+    remove_stale_status(backend, ctx, uri, &report);
+
+    let mut indv = Individual::default();
+    indv.set_id(uri);
+    indv.add_uri("v-s:deliveryStatus", report.status.as_uri());
+    indv.add_datetime("v-s:deliveryDate", Utc::now().timestamp());
+    indv.add_integer("v-s:deliveryAttempts", report.attempt);
+    indv.add_bool("v-s:isSuccess", matches!(report.status, DeliveryStatus::Sent));
+    if let Some(code) = report.smtp_code {
+        indv.add_integer("v-s:smtpCode", code);
+    }
+    if let Some(message_id) = &report.message_id {
+        indv.add_string("v-s:smtpMessageId", message_id, Lang::none());
+    }
+    if let Some(error) = &report.error {
+        indv.add_string("v-s:deliveryError", error, Lang::none());
+    }
+    for attachment in report.linked_attachments.iter() {
+        indv.add_uri("v-s:attachmentSentAsLink", attachment);
+    }
+
+    let res = backend.mstorage_api.update(&ctx.sys_ticket, IndvOp::SetIn, &indv);
+    if res.result != ResultCode::Ok {
+        error!("failed to write delivery status, uri = {}, err = {:?}", uri, res.result);
+    }
+}
+
+fn remove_stale_status(backend: &mut Backend, ctx: &Context, uri: &str, report: &DeliveryReport) {
+    let mut current = Individual::default();
+    if backend.storage.get_individual(uri, &mut current) != ResultCode::Ok {
+        return;
+    }
+    current.parse_all();
+
+    let mut indv = Individual::default();
+    indv.set_id(uri);
+    let mut is_stale = false;
+    if report.smtp_code.is_none() {
+        if let Some(code) = current.get_first_integer("v-s:smtpCode") {
+            indv.add_integer("v-s:smtpCode", code);
+            is_stale = true;
+        }
+    }
+    if report.message_id.is_none() {
+        for message_id in current.get_literals("v-s:smtpMessageId").unwrap_or_default() {
+            indv.add_string("v-s:smtpMessageId", &message_id, Lang::none());
+            is_stale = true;
+        }
+    }
+    if report.error.is_none() {
+        for error in current.get_literals("v-s:deliveryError").unwrap_or_default() {
+            indv.add_string("v-s:deliveryError", &error, Lang::none());
+            is_stale = true;
+        }
+    }
+    if report.linked_attachments.is_empty() {
+        for attachment in current.get_literals("v-s:attachmentSentAsLink").unwrap_or_default() {
+            indv.add_uri("v-s:attachmentSentAsLink", &attachment);
+            is_stale = true;
+        }
+    }
+    if !is_stale {
+        return;
+    }
+
+    let res = backend.mstorage_api.update(&ctx.sys_ticket, IndvOp::RemoveFrom, &indv);
+    if res.result != ResultCode::Ok {
+        error!("failed to remove previous delivery status, uri = {}, err = {:?}", uri, res.result);
+    }
+}
+
+// The status is written by fanout-email itself, such change of the message must not be sent again.
+// The removal of values of the previous attempt keeps the delivery date, it is told by the removed status predicates
+pub fn is_status_update(new_state: &mut Individual, prev_state: &mut Individual) -> bool {
+    let new_date = new_state.get_first_datetime("v-s:deliveryDate");
+    if new_date.is_some() && new_date != prev_state.get_first_datetime("v-s:deliveryDate") {
+        return true;
+    }
+    OPTIONAL_STATUS_PREDICATES.iter().any(|predicate| prev_state.is_exists(predicate) && !new_state.is_exists(predicate))
+}
@@ -0,0 +1,175 @@
+use chrono::NaiveDateTime;
+use v_common::module::veda_backend::Backend;
+use v_common::onto::individual::Individual;
+use v_common::onto::resource::{Resource, Value};
+
+// Depth of the links followed by a placeholder, it protects from the cycles of links
+const MAX_PATH_LENGTH: usize = 5;
+
+// Returns the subject and body of message. If the message refers to a template by v-s:hasMessageTemplate, they are rendered from
+// v-s:notificationSubject and v-s:notificationBody of the template, otherwise v-s:subject and v-s:messageBody are used as is.
+// A placeholder {{ predicate }} is replaced by the values of predicate of the message, {{ predicate.predicate2 }} follows the link
+// to the individual and takes its predicate2, e.g. {{ v-s:onDocument.rdfs:label }}
+pub fn render_message(msg_indv: &mut Individual, backend: &mut Backend) -> (Option<String>, Option<String>) {
+    let subject = msg_indv.get_first_literal("v-s:subject");
+    let body = msg_indv.get_first_literal("v-s:messageBody");
+
+    let template_uri = match msg_indv.get_first_literal("v-s:hasMessageTemplate") {
+        Some(uri) => uri,
+        None => return (subject, body),
+    };
+    let mut template = Individual::default();
+    if backend.get_individual(&template_uri, &mut template).is_none() {
+        error!("template {} not found, message {} is sent as is", template_uri, msg_indv.get_id());
+        return (subject, body);
+    }
+    template.parse_all();
+
+    // The language of message, then the language of template, values without language are used if there are no values of the language
+    let lang = msg_indv
+        .get_first_literal("v-s:notificationLanguage")
+        .or_else(|| template.get_first_literal("v-s:notificationLanguage"))
+        .map(|lang| lang.trim_start_matches("v-ui:").to_uppercase())
+        .unwrap_or_default();
+
+    let subject_template = select_by_lang(template.get_resources("v-s:notificationSubject").unwrap_or_default(), &lang).first().map(resource_to_string);
+    let body_template = select_by_lang(template.get_resources("v-s:notificationBody").unwrap_or_default(), &lang).first().map(resource_to_string);
+    if body_template.is_none() {
+        warn!("template {} has no body, message {} is sent as is", template_uri, msg_indv.get_id());
+        return (subject, body);
+    }
+
+    let subject = subject_template.map(|text| render(&text, msg_indv, &lang, false, backend)).or(subject);
+    let body = body_template.map(|text| {
+        let is_html = text.to_lowercase().contains("<html>");
+        render(&text, msg_indv, &lang, is_html, backend)
+    });
+    (subject, body)
+}
+
+fn render(text: &str, msg_indv: &mut Individual, lang: &str, is_html: bool, backend: &mut Backend) -> String {
+    render_placeholders(text, is_html, |path| {
+        let values = if path.len() > MAX_PATH_LENGTH {
+            vec![]
+        } else {
+            resolve(msg_indv, path, lang, backend)
+        };
+        if values.is_empty() {
+            warn!("placeholder {{{{ {} }}}} of message {} has no value", path.join("."), msg_indv.get_id());
+        }
+        values
+    })
+}
+
+// Replaces every placeholder by the values returned by [resolve_path] for the path of predicates of placeholder
+fn render_placeholders<F: FnMut(&[&str]) -> Vec<String>>(text: &str, is_html: bool, mut resolve_path: F) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        result.push_str(&rest[..start]);
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => {
+                rest = &rest[start..];
+                break;
+            },
+        };
+
+        let placeholder = rest[start + 2..end].trim();
+        let path: Vec<&str> = placeholder.split('.').map(|predicate| predicate.trim()).collect();
+        let value = resolve_path(&path).join(", ");
+        if is_html {
+            result.push_str(&escape_html(&value));
+        } else {
+            result.push_str(&value);
+        }
+        rest = &rest[end + 2..];
+    }
+    result.push_str(rest);
+    result
+}
+
+fn resolve(indv: &mut Individual, path: &[&str], lang: &str, backend: &mut Backend) -> Vec<String> {
+    let resources = select_by_lang(indv.get_resources(path[0]).unwrap_or_default(), lang);
+    if path.len() == 1 {
+        return resources.iter().map(resource_to_string).collect();
+    }
+
+    let mut values = vec![];
+    for resource in resources.iter() {
+        if let Value::Uri(uri) = &resource.value {
+            let mut linked = Individual::default();
+            if backend.get_individual(uri, &mut linked).is_some() {
+                linked.parse_all();
+                values.append(&mut resolve(&mut linked, &path[1..], lang, backend));
+            }
+        }
+    }
+    values
+}
+
+fn select_by_lang(resources: Vec<Resource>, lang: &str) -> Vec<Resource> {
+    if !lang.is_empty() {
+        let matched: Vec<Resource> = resources.iter().filter(|resource| resource.get_lang().to_string().to_uppercase() == lang).cloned().collect();
+        if !matched.is_empty() {
+            return matched;
+        }
+    }
+    let neutral: Vec<Resource> = resources.iter().filter(|resource| !resource.get_lang().is_some()).cloned().collect();
+    if !neutral.is_empty() {
+        return neutral;
+    }
+    resources
+}
+
+fn resource_to_string(resource: &Resource) -> String {
+    match &resource.value {
+        Value::Str(str_value, _lang) => str_value.to_owned(),
+        Value::Uri(uri_value) => uri_value.to_owned(),
+        Value::Int(int_value) => int_value.to_string(),
+        Value::Bool(bool_value) => bool_value.to_string(),
+        Value::Num(_m, _e) => resource.get_float().to_string(),
+        Value::Datetime(timestamp) => NaiveDateTime::from_timestamp(*timestamp, 0).format("%d.%m.%Y %H:%M").to_string(),
+        _ => String::new(),
+    }
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(path: &[&str]) -> Vec<String> {
+        match path {
+            ["v-s:number"] => vec!["42".to_owned()],
+            ["v-s:onDocument", "rdfs:label"] => vec!["Contract".to_owned(), "<Draft>".to_owned()],
+            _ => vec![],
+        }
+    }
+
+    #[test]
+    fn render_replaces_placeholders() {
+        assert_eq!(render_placeholders("Task {{v-s:number}} is done", false, values), "Task 42 is done");
+        assert_eq!(render_placeholders("{{ v-s:onDocument . rdfs:label }}", false, values), "Contract, <Draft>");
+    }
+
+    #[test]
+    fn render_escapes_values_in_html() {
+        assert_eq!(render_placeholders("<p>{{v-s:onDocument.rdfs:label}}</p>", true, values), "<p>Contract, &lt;Draft&gt;</p>");
+    }
+
+    #[test]
+    fn render_keeps_text_without_placeholders() {
+        assert_eq!(render_placeholders("no value: {{v-s:unknown}}.", false, values), "no value: .");
+        assert_eq!(render_placeholders("unclosed {{v-s:number", false, values), "unclosed {{v-s:number");
+        assert_eq!(render_placeholders("", false, values), "");
+    }
+
+    #[test]
+    fn escape_html_replaces_special_chars() {
+        assert_eq!(escape_html("a < b && c > \"d\""), "a &lt; b &amp;&amp; c &gt; &quot;d&quot;");
+    }
+}
@@ -0,0 +1,63 @@
+use chrono::Utc;
+use lettre::{Message, SmtpTransport};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub enum MailTransport {
+    Smtp(SmtpTransport),
+    // Messages are written to files of the directory, they are used by tests and local development
+    Local(LocalTransport),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LocalFormat {
+    // One <message uri>.eml file per message, the next sending of the message replaces the file
+    File,
+    // Messages are delivered into the new subdirectory of maildir, every sending gets its own file
+    Maildir,
+}
+
+pub struct LocalTransport {
+    dir: String,
+    format: LocalFormat,
+}
+
+impl LocalTransport {
+    pub fn new(dir: &str, format: LocalFormat) -> io::Result<Self> {
+        if format == LocalFormat::Maildir {
+            for sub_dir in ["tmp", "new", "cur"] {
+                fs::create_dir_all(Path::new(dir).join(sub_dir))?;
+            }
+        } else {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(LocalTransport {
+            dir: dir.to_owned(),
+            format,
+        })
+    }
+
+    // Writes the built message, returns the path of the written file
+    pub fn send(&self, email: &Message, uri: &str) -> io::Result<String> {
+        let name = urlencoding::encode(uri).to_string();
+        let path = match self.format {
+            LocalFormat::File => {
+                let path = Path::new(&self.dir).join(format!("{}.eml", name));
+                fs::write(&path, email.formatted())?;
+                path
+            },
+            LocalFormat::Maildir => {
+                // The message is written to tmp and moved to new, so a reader of maildir never sees a partially written file
+                let now = Utc::now();
+                let unique = format!("{}.{}_{}.{}", now.timestamp(), now.timestamp_subsec_micros(), std::process::id(), name);
+                let tmp_path = Path::new(&self.dir).join("tmp").join(&unique);
+                let path = Path::new(&self.dir).join("new").join(&unique);
+                fs::write(&tmp_path, email.formatted())?;
+                fs::rename(&tmp_path, &path)?;
+                path
+            },
+        };
+        Ok(path.to_string_lossy().to_string())
+    }
+}
@@ -0,0 +1,46 @@
+use chrono::Utc;
+use v_common::onto::datatype::Lang;
+use v_common::onto::individual::Individual;
+use v_common::onto::individual2msgpack::to_msgpack;
+use v_common::v_queue::queue::Queue;
+use v_common::v_queue::record::{Mode, MsgType};
+
+pub const UNDELIVERABLE_QUEUE_PATH: &str = "./data/email-undeliverable";
+
+// Queue of the messages which can not be delivered, every message holds the uri of the message individual, the error text and the count of attempts
+pub struct UndeliverableQueue {
+    queue: Queue,
+}
+
+impl UndeliverableQueue {
+    pub fn new(name: &str) -> Result<Self, &'static str> {
+        match Queue::new(UNDELIVERABLE_QUEUE_PATH, name, Mode::ReadWrite) {
+            Ok(queue) => Ok(UndeliverableQueue {
+                queue,
+            }),
+            Err(e) => {
+                error!("failed to open undeliverable queue, err = {:?}", e);
+                Err("failed to open undeliverable queue")
+            },
+        }
+    }
+
+    pub fn push(&mut self, uri: &str, attempts: i64, err: &str) {
+        let mut msg = Individual::default();
+        msg.set_id(uri);
+        msg.add_string("error", err, Lang::none());
+        msg.add_integer("attempts", attempts);
+        msg.add_datetime("date", Utc::now().timestamp());
+
+        let mut raw: Vec<u8> = Vec::new();
+        if let Err(e) = to_msgpack(&msg, &mut raw) {
+            error!("failed to serialize undeliverable message, uri = {}, err = {:?}", uri, e);
+            return;
+        }
+        if let Err(e) = self.queue.push(&raw, MsgType::Object) {
+            error!("failed to push into undeliverable queue, uri = {}, err = {:?}", uri, e);
+            return;
+        }
+        error!("message is undeliverable, uri = {}, attempts = {}, err = {}", uri, attempts, err);
+    }
+}
@@ -0,0 +1,119 @@
+use crate::dead_letter::serialize_element;
+use crate::{class_table, export, flush_batch, is_exportable, is_exportable_class, is_own_shard, merge_ops, remove, Context, RemoveMode, TableMode, BATCH_SIZE};
+use std::process;
+use v_common::module::info::ModuleInfo;
+use v_common::module::veda_backend::Backend;
+use v_common::onto::individual::Individual;
+use v_common::onto::individual2msgpack::to_msgpack;
+use v_common::search::common::FTQuery;
+use v_common::v_api::api_client::IndvOp;
+use v_common::v_api::obj::{QueryResult, ResultCode};
+
+// Exports every exportable individual of storage, the position is stored in module info <consumer>_backfill,
+// so the interrupted backfill continues from the last written page
+pub fn backfill(backend: &mut Backend, ctx: &mut Context, consumer_name: &str) {
+    let module_info = ModuleInfo::new("./data", &format!("{}_backfill", consumer_name), true);
+    if module_info.is_err() {
+        error!("failed to start backfill, err = {:?}", module_info.err());
+        process::exit(101);
+    }
+    let mut module_info = module_info.unwrap();
+
+    let mut pos = module_info.read_info().map(|(pos, _)| pos).unwrap_or(0);
+    info!("start backfill from position {}", pos);
+
+    loop {
+        let res = read_exportable_page(backend, pos);
+        if res.result_code != ResultCode::Ok {
+            error!("failed to read individuals for backfill, position = {}, err = {:?}", pos, res.result_code);
+            process::exit(101);
+        }
+        if res.result.is_empty() {
+            break;
+        }
+
+        for id in res.result.iter() {
+            pos += 1;
+            if !is_own_shard(ctx, id) {
+                continue;
+            }
+            let mut indv = Individual::default();
+            if backend.storage.get_individual(id, &mut indv) == ResultCode::Ok {
+                indv.parse_all();
+                ctx.source = backfill_element(&mut indv);
+                backfill_individual(backend, ctx, &mut indv);
+            }
+        }
+
+        if flush_batch(ctx).is_err() {
+            error!("failed to export backfill batch, position = {}", pos);
+            process::exit(101);
+        }
+        if let Err(e) = module_info.put_info(pos, pos) {
+            error!("failed to write module_info, err = {:?}", e);
+            process::exit(101);
+        }
+        info!("backfill position = {}", pos);
+    }
+    info!("backfill is complete, processed {} individuals", pos);
+}
+
+// Reads the page of uris of the individuals of exportable classes, the individuals are ordered by creation date
+pub fn read_exportable_page(backend: &mut Backend, from: i64) -> QueryResult {
+    let req = FTQuery {
+        ticket: "".to_string(),
+        user: "cfg:VedaSystem".to_string(),
+        query: "'rdf:type' == 'v-s:Exportable'".to_string(),
+        sort: "'v-s:created' asc".to_string(),
+        databases: "".to_string(),
+        reopen: false,
+        top: BATCH_SIZE as i32,
+        limit: BATCH_SIZE as i32,
+        from: from as i32,
+    };
+    backend.fts.query(req)
+}
+
+// Queue element for the dead letter queue, it is exported again by backfill_individual
+pub fn backfill_element(indv: &mut Individual) -> Vec<u8> {
+    let mut raw: Vec<u8> = Vec::new();
+    if let Err(e) = to_msgpack(indv, &mut raw) {
+        error!("failed to serialize individual, uri = {}, err = {:?}", indv.get_id(), e);
+    }
+    let mut element = Individual::default();
+    element.set_id(indv.get_id());
+    element.add_binary("new_state", raw);
+    element.add_integer("cmd", IndvOp::Put as i64);
+    element.add_bool("backfill", true);
+    serialize_element(&mut element)
+}
+
+// Rows written by the previous exports are removed, so the backfill may be repeated without duplicates
+pub fn backfill_individual(backend: &mut Backend, ctx: &mut Context, indv: &mut Individual) {
+    let classes = match indv.get_literals("rdf:type") {
+        Some(classes) => classes,
+        None => return,
+    };
+
+    let start = ctx.batch.len();
+    let res = if ctx.table_mode == TableMode::Class {
+        let exportable: Vec<String> = classes.into_iter().filter(|class| is_exportable_class(backend, ctx, class)).collect();
+        class_table::remove(indv, &exportable, RemoveMode::Delete, ctx);
+        class_table::export(indv, &mut Individual::default(), &exportable, true, ctx)
+    } else if is_exportable(backend, ctx, &classes) {
+        remove(indv, &classes, RemoveMode::Delete, ctx);
+        export(indv, &mut Individual::default(), &classes, true, ctx)
+    } else {
+        Ok(())
+    };
+
+    if let Err(e) = res {
+        // The individual goes to the dead letter queue, the removal is dropped too, so the existing rows are kept
+        ctx.batch.truncate(start);
+        ctx.dead_letter.push(indv.get_id(), &ctx.source, e);
+        return;
+    }
+
+    // Removal and export of individual are written or rolled back together
+    merge_ops(ctx, start, indv.get_id());
+}
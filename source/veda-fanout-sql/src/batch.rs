@@ -0,0 +1,78 @@
+use crate::dead_letter::Source;
+use crate::dialect::{ClassRow, HistoryRow, PredicateRow, SqlDialect};
+use std::collections::HashMap;
+
+pub enum BatchOp {
+    Insert(String, PredicateRow),
+    InsertRow(String, ClassRow),
+    InsertHistory(String, HistoryRow),
+    Delete(String, String),
+    MarkDeleted(String, String),
+}
+
+// Changes of one individual, they are written or rolled back together
+pub struct IndividualOps {
+    pub uri: String,
+    pub ops: Vec<BatchOp>,
+    // Queue element the changes are made from, it goes to the dead letter queue if they fail
+    pub source: Source,
+}
+
+pub enum BatchResult {
+    Committed,
+    RolledBack(&'static str),
+}
+
+// Writes the changes in one transaction, inserts into the same table are joined into multi-row statements.
+// Returns RolledBack if a statement failed and the transaction was rolled back
+pub fn write_batch(db: &mut dyn SqlDialect, batch: &[IndividualOps]) -> Result<BatchResult, &'static str> {
+    db.begin()?;
+
+    if let Err(e) = apply_ops(db, batch) {
+        db.rollback()?;
+        return Ok(BatchResult::RolledBack(e));
+    }
+
+    db.commit()?;
+    Ok(BatchResult::Committed)
+}
+
+fn apply_ops(db: &mut dyn SqlDialect, batch: &[IndividualOps]) -> Result<(), &'static str> {
+    let mut pending: HashMap<&str, Vec<&PredicateRow>> = HashMap::new();
+    // History rows are only appended, so they are written after the other changes
+    let mut pending_history: HashMap<&str, Vec<&HistoryRow>> = HashMap::new();
+
+    for indv in batch {
+        for op in &indv.ops {
+            match op {
+                BatchOp::Insert(table, row) => pending.entry(table.as_str()).or_default().push(row),
+                BatchOp::InsertRow(table, row) => db.insert_row(table, row)?,
+                BatchOp::InsertHistory(table, row) => pending_history.entry(table.as_str()).or_default().push(row),
+                BatchOp::Delete(table, doc_id) | BatchOp::MarkDeleted(table, doc_id) => {
+                    // Rows of this document collected earlier must be written before they are removed
+                    if let Some(rows) = pending.get_mut(table.as_str()) {
+                        if rows.iter().any(|row| row.doc_id == *doc_id) {
+                            db.insert(table, rows)?;
+                            rows.clear();
+                        }
+                    }
+                    if let BatchOp::Delete(..) = op {
+                        db.delete(table, doc_id)?;
+                    } else {
+                        db.mark_deleted(table, doc_id)?;
+                    }
+                },
+            }
+        }
+    }
+
+    for (table, rows) in pending.iter() {
+        if !rows.is_empty() {
+            db.insert(table, rows)?;
+        }
+    }
+    for (table, rows) in pending_history.iter() {
+        db.insert_history(table, rows)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,214 @@
+use crate::batch::BatchOp;
+use crate::dialect::{resource_lang, resource_to_value, ClassRow, ColumnType, PredicateRow, SqlValue};
+use crate::{check_create_predicate_table, push_ops, Context, RemoveMode};
+use chrono::NaiveDateTime;
+use std::collections::HashMap;
+use v_common::onto::individual::Individual;
+use v_common::onto::resource::Resource;
+
+// Columns of the class table which are not predicates
+const ROW_COLUMNS: [&str; 3] = ["doc_id", "created", "deleted"];
+
+// Name of table or column: lowercase, every char except letters and digits is replaced by '_'
+fn to_sql_name(ctx: &Context, name: &str) -> String {
+    let sql_name: String = name
+        .to_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    ctx.db.ident_name(&sql_name)
+}
+
+// Multi-valued predicates are stored in the child table <class table>__<column>, it has the same layout as the predicate table
+fn child_table_name(ctx: &Context, table: &str, column: &str) -> String {
+    ctx.db.ident_name(&format!("{}__{}", table, column))
+}
+
+// Replaces the rows of individual in the tables of its classes, returns Err if the export is aborted, the caller sends the element to the dead letter queue
+pub fn export(new_state: &mut Individual, prev_state: &mut Individual, classes: &[String], is_new: bool, ctx: &mut Context) -> Result<(), &'static str> {
+    let uri = new_state.get_id().to_string();
+
+    if classes.is_empty() {
+        return Ok(());
+    }
+    if classes.contains(&"v-s:Version".to_owned()) {
+        info!("skip version: {}", uri);
+        return Ok(());
+    }
+
+    // The classes are filtered by exportable, so v-s:Deletable is looked for in all types of individual
+    let is_deleted = new_state.is_exists_bool("v-s:deleted", true) || new_state.get_literals("rdf:type").unwrap_or_default().contains(&"v-s:Deletable".to_owned());
+    let created = new_state.get_first_datetime("v-s:created").map(|timestamp| NaiveDateTime::from_timestamp(timestamp, 0));
+
+    let mut ops = vec![];
+    for class in classes {
+        if class == "v-s:Deletable" {
+            continue;
+        }
+        let table = to_sql_name(ctx, class);
+        if check_create_class_table(ctx, &table).is_err() {
+            error!("failed to create class table, export aborted, class = {}, uri = {}", class, uri);
+            return Err("failed to create class table");
+        }
+
+        // The row is deleted even if the individual is new, it may be already exported by the backfill or before the redrive
+        ops.push(BatchOp::Delete(table.clone(), uri.clone()));
+        let mut predicates = new_state.get_predicates();
+        if !is_new {
+            for predicate in prev_state.get_predicates() {
+                if !predicates.contains(&predicate) {
+                    predicates.push(predicate);
+                }
+            }
+        }
+        for predicate in predicates.iter() {
+            let child = child_table_name(ctx, &table, &to_sql_name(ctx, predicate));
+            if ctx.tables.contains_key(&child) {
+                ops.push(BatchOp::Delete(child, uri.clone()));
+            }
+        }
+
+        let mut row = ClassRow {
+            doc_id: uri.clone(),
+            created,
+            deleted: is_deleted,
+            values: vec![],
+        };
+
+        for predicate in new_state.get_predicates() {
+            let resources = match new_state.get_resources(&predicate) {
+                Some(resources) if !resources.is_empty() => resources,
+                _ => continue,
+            };
+            let column = to_sql_name(ctx, &predicate);
+            if check_create_column(ctx, &table, &column, &resources[0]).is_err() {
+                warn!("skip property {} of class {}, uri = {}", predicate, class, uri);
+                continue;
+            }
+            row.values.push((column.clone(), resource_to_value(&resources[0])));
+
+            if resources.len() > 1 {
+                let child = child_table_name(ctx, &table, &column);
+                for resource in resources.iter() {
+                    let value = resource_to_value(resource);
+                    if let SqlValue::Null = value {
+                        continue;
+                    }
+                    if check_create_predicate_table(&mut ctx.tables, &child, resource, false, ctx.db.as_mut()).is_err() {
+                        error!("failed to create child table, export aborted, property = {}, uri = {}", predicate, uri);
+                        return Err("failed to create child table");
+                    }
+                    ops.push(BatchOp::Insert(
+                        child.clone(),
+                        PredicateRow {
+                            doc_id: uri.clone(),
+                            doc_type: class.to_string(),
+                            created,
+                            value,
+                            lang: resource_lang(resource),
+                            deleted: is_deleted,
+                        },
+                    ));
+                }
+            }
+        }
+        // Every column of the table gets a value, so the insert statement is the same for all rows of the table
+        if let Some(columns) = ctx.columns.get(&table) {
+            for column in columns.keys() {
+                if !ROW_COLUMNS.contains(&column.as_str()) && !row.values.iter().any(|(name, _)| name == column) {
+                    row.values.push((column.to_owned(), SqlValue::Null));
+                }
+            }
+        }
+        row.values.sort_by(|a, b| a.0.cmp(&b.0));
+        ops.push(BatchOp::InsertRow(table, row));
+    }
+
+    debug!("prepared uri = {}, classes = {:?}, statements = {}", uri, classes, ops.len());
+    push_ops(ctx, uri, ops);
+    Ok(())
+}
+
+// Removes the rows of individual from the tables of given classes and their child tables, or marks them as deleted
+pub fn remove(prev_state: &mut Individual, classes: &[String], remove_mode: RemoveMode, ctx: &mut Context) {
+    let uri = prev_state.get_id().to_string();
+
+    if classes.contains(&"v-s:Version".to_owned()) {
+        info!("skip version: {}", uri);
+        return;
+    }
+
+    let mut tables = vec![];
+    for class in classes {
+        let table = to_sql_name(ctx, class);
+        if !ctx.columns.contains_key(&table) {
+            continue;
+        }
+        for predicate in prev_state.get_predicates() {
+            let child = child_table_name(ctx, &table, &to_sql_name(ctx, &predicate));
+            if ctx.tables.contains_key(&child) {
+                tables.push(child);
+            }
+        }
+        tables.push(table);
+    }
+
+    let ops: Vec<BatchOp> = tables
+        .into_iter()
+        .map(|table| match remove_mode {
+            RemoveMode::Delete => BatchOp::Delete(table, uri.clone()),
+            RemoveMode::MarkDeleted => BatchOp::MarkDeleted(table, uri.clone()),
+        })
+        .collect();
+
+    if !ops.is_empty() {
+        info!("remove uri = {}, classes = {:?}, mode = {:?}", uri, classes, remove_mode);
+        push_ops(ctx, uri, ops);
+    }
+}
+
+fn check_create_class_table(ctx: &mut Context, table: &str) -> Result<(), &'static str> {
+    if ctx.columns.contains_key(table) {
+        return Ok(());
+    }
+    ctx.db.create_class_table(table)?;
+    ctx.columns.insert(table.to_owned(), HashMap::new());
+    Ok(())
+}
+
+fn check_create_column(ctx: &mut Context, table: &str, column: &str, resource: &Resource) -> Result<(), &'static str> {
+    let column_type = match ColumnType::from_data_type(&resource.rtype) {
+        Some(column_type) => column_type,
+        None => {
+            error!("unsupported property value type: {:#?}", resource.rtype);
+            return Err("unsupported property value type");
+        },
+    };
+    let existing = ctx.columns.get(table).and_then(|columns| columns.get(column)).copied();
+    let column_type = match existing {
+        None => {
+            ctx.db.add_column(table, column, column_type)?;
+            column_type
+        },
+        Some(existing) => {
+            let widened = existing.widen(column_type);
+            if widened == existing {
+                return Ok(());
+            }
+            warn!(
+                "type conflict in class table {}, column {}: column type = {:?}, value type = {:?}, widen column to {:?}",
+                table, column, existing, column_type, widened
+            );
+            ctx.db.widen_column(table, column, existing, widened)?;
+            widened
+        },
+    };
+    ctx.columns.entry(table.to_owned()).or_default().insert(column.to_owned(), column_type);
+    Ok(())
+}
@@ -0,0 +1,180 @@
+use crate::backfill::backfill_individual;
+use crate::{flush_batch, Context};
+use chrono::Utc;
+use std::process;
+use v_common::module::module_impl::get_inner_binobj_as_individual;
+use v_common::module::veda_backend::Backend;
+use v_common::onto::datatype::Lang;
+use v_common::onto::individual::{Individual, RawObj};
+use v_common::onto::individual2msgpack::to_msgpack;
+use v_common::onto::parser::parse_raw;
+use v_common::v_queue::consumer::Consumer;
+use v_common::v_queue::queue::Queue;
+use v_common::v_queue::record::{Mode, MsgType};
+
+pub const DEAD_LETTER_QUEUE_PATH: &str = "./data/dead-letter";
+
+// Queue of the elements which failed to export, every message holds the source queue element and the error text
+pub struct DeadLetterQueue {
+    queue: Queue,
+}
+
+impl DeadLetterQueue {
+    pub fn new(name: &str) -> Result<Self, &'static str> {
+        match Queue::new(DEAD_LETTER_QUEUE_PATH, name, Mode::ReadWrite) {
+            Ok(queue) => Ok(DeadLetterQueue {
+                queue,
+            }),
+            Err(e) => {
+                error!("failed to open dead letter queue, err = {:?}", e);
+                Err("failed to open dead letter queue")
+            },
+        }
+    }
+
+    pub fn push(&mut self, uri: &str, source: &[u8], err: &str) {
+        let mut msg = Individual::default();
+        msg.set_id(uri);
+        msg.add_binary("queue_element", source.to_vec());
+        msg.add_string("error", err, Lang::none());
+        msg.add_datetime("date", Utc::now().timestamp());
+
+        let mut raw: Vec<u8> = Vec::new();
+        if let Err(e) = to_msgpack(&msg, &mut raw) {
+            error!("failed to serialize dead letter, uri = {}, err = {:?}", uri, e);
+            return;
+        }
+        if let Err(e) = self.queue.push(&raw, MsgType::Object) {
+            error!("failed to push into dead letter queue, uri = {}, err = {:?}", uri, e);
+            return;
+        }
+        warn!("moved to dead letter queue, uri = {}, err = {}", uri, err);
+    }
+}
+
+// Queue element the changes are made from, the element from the queue is serialized only when it goes to the dead letter queue
+pub enum Source {
+    // Element of backfill, redrive or reconciliation, it is serialized before the export
+    Serialized(Vec<u8>),
+    // States of the element from the queue, the element is built from them again
+    States {
+        cmd: Option<i64>,
+        op_id: Option<i64>,
+        date: Option<i64>,
+        new_state: Option<Individual>,
+        prev_state: Option<Individual>,
+    },
+}
+
+impl Source {
+    pub fn serialize(&mut self) -> Vec<u8> {
+        match self {
+            Source::Serialized(raw) => raw.clone(),
+            Source::States {
+                cmd,
+                op_id,
+                date,
+                new_state,
+                prev_state,
+            } => {
+                let mut element = Individual::default();
+                if let Some(state) = prev_state {
+                    add_state(&mut element, "prev_state", state);
+                }
+                if let Some(state) = new_state {
+                    add_state(&mut element, "new_state", state);
+                }
+                if let Some(cmd) = cmd {
+                    element.add_integer("cmd", *cmd);
+                }
+                if let Some(op_id) = op_id {
+                    element.add_integer("op_id", *op_id);
+                }
+                if let Some(date) = date {
+                    element.add_datetime("date", *date);
+                }
+                serialize_element(&mut element)
+            },
+        }
+    }
+}
+
+fn add_state(element: &mut Individual, predicate: &str, state: &mut Individual) {
+    state.parse_all();
+    let mut raw: Vec<u8> = Vec::new();
+    if let Err(e) = to_msgpack(state, &mut raw) {
+        error!("failed to serialize individual, uri = {}, err = {:?}", state.get_id(), e);
+    }
+    element.set_id(state.get_id());
+    element.add_binary(predicate, raw);
+}
+
+// Serializes the queue element
+pub fn serialize_element(queue_element: &mut Individual) -> Vec<u8> {
+    queue_element.parse_all();
+    let mut raw: Vec<u8> = Vec::new();
+    if let Err(e) = to_msgpack(queue_element, &mut raw) {
+        error!("failed to serialize queue element, uri = {}, err = {:?}", queue_element.get_id(), e);
+    }
+    raw
+}
+
+// Exports again the elements of the dead letter queue, the elements which fail again are pushed to the end of queue
+pub fn redrive(backend: &mut Backend, ctx: &mut Context, consumer_name: &str) {
+    let mut consumer = match Consumer::new(DEAD_LETTER_QUEUE_PATH, "redrive", consumer_name) {
+        Ok(consumer) => consumer,
+        Err(e) => {
+            error!("failed to open dead letter queue, err = {:?}", e);
+            process::exit(101);
+        },
+    };
+    if let Err(e) = consumer.queue.get_info_of_part(consumer.id, true) {
+        error!("failed to get_info_of_part {}: {}", consumer.id, e.as_str());
+        process::exit(101);
+    }
+
+    let size_batch = consumer.get_batch_size();
+    info!("redrive {} elements of dead letter queue", size_batch);
+
+    let mut count = 0;
+    for _idx in 0..size_batch {
+        if !consumer.pop_header() {
+            break;
+        }
+        let mut raw = RawObj::new(vec![0; (consumer.header.msg_length) as usize]);
+        if let Err(e) = consumer.pop_body(&mut raw.data) {
+            error!("failed to read dead letter, err = {}", e.as_str());
+            break;
+        }
+        count += 1;
+
+        let mut msg = Individual::new_raw(raw);
+        if parse_raw(&mut msg).is_err() {
+            error!("failed to parse dead letter, skip");
+            continue;
+        }
+        info!("redrive uri = {}, err = {}", msg.get_id(), msg.get_first_literal("error").unwrap_or_default());
+
+        let mut queue_element = Individual::default();
+        if !get_inner_binobj_as_individual(&mut msg, "queue_element", &mut queue_element) {
+            error!("dead letter has no queue element, skip, uri = {}", msg.get_id());
+            continue;
+        }
+
+        if queue_element.is_exists_bool("backfill", true) {
+            let mut indv = Individual::default();
+            get_inner_binobj_as_individual(&mut queue_element, "new_state", &mut indv);
+            ctx.source = serialize_element(&mut queue_element);
+            backfill_individual(backend, ctx, &mut indv);
+        } else if crate::process(backend, ctx, &mut queue_element, &consumer).is_err() {
+            error!("failed to redrive, uri = {}", msg.get_id());
+        }
+    }
+
+    if flush_batch(ctx).is_err() {
+        error!("failed to export redriven elements, dead letter queue position is not committed");
+        process::exit(101);
+    }
+    consumer.commit();
+    info!("redrive is complete, processed {} elements", count);
+}
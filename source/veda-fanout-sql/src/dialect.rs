@@ -0,0 +1,290 @@
+use crate::dialect_mysql::MySqlDialect;
+use crate::dialect_postgresql::PostgreSqlDialect;
+use crate::dialect_sqlite::SqliteDialect;
+use crate::fnv1a_hash;
+use chrono::NaiveDateTime;
+use std::collections::{HashMap, HashSet};
+use std::{thread, time};
+use v_common::module::veda_backend::Backend;
+use v_common::onto::datatype::DataType;
+use v_common::onto::individual::Individual;
+use v_common::onto::resource::{Resource, Value};
+use v_common::v_api::obj::ResultCode;
+
+#[derive(Debug, Clone)]
+pub enum SqlValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Decimal(f64),
+    Str(String),
+    Datetime(NaiveDateTime),
+}
+
+impl SqlValue {
+    // Text form of the value, it is used for the column widened to TEXT and to compare values
+    pub fn to_text(&self) -> String {
+        match self {
+            SqlValue::Null => String::new(),
+            SqlValue::Bool(bool_value) => bool_value.to_string(),
+            SqlValue::Int(int_value) => int_value.to_string(),
+            SqlValue::Decimal(num_value) => num_value.to_string(),
+            SqlValue::Str(str_value) => str_value.to_owned(),
+            SqlValue::Datetime(datetime) => datetime.format("%Y-%m-%d %H:%M:%S").to_string(),
+        }
+    }
+}
+
+// Type of the column, a column is widened when a value of another type arrives: Bool -> Integer -> Decimal -> Text, Uri and Datetime -> Text
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    Bool,
+    Integer,
+    Decimal,
+    Datetime,
+    Uri,
+    Text,
+}
+
+impl ColumnType {
+    pub fn from_data_type(rtype: &DataType) -> Option<ColumnType> {
+        match rtype {
+            DataType::Boolean => Some(ColumnType::Bool),
+            DataType::Integer => Some(ColumnType::Integer),
+            DataType::Decimal => Some(ColumnType::Decimal),
+            DataType::Datetime => Some(ColumnType::Datetime),
+            DataType::Uri => Some(ColumnType::Uri),
+            DataType::String => Some(ColumnType::Text),
+            _ => None,
+        }
+    }
+
+    // Returns the type able to hold the values of both types
+    pub fn widen(self, other: ColumnType) -> ColumnType {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (ColumnType::Bool, ColumnType::Integer) | (ColumnType::Integer, ColumnType::Bool) => ColumnType::Integer,
+            (ColumnType::Bool, ColumnType::Decimal) | (ColumnType::Decimal, ColumnType::Bool) => ColumnType::Decimal,
+            (ColumnType::Integer, ColumnType::Decimal) | (ColumnType::Decimal, ColumnType::Integer) => ColumnType::Decimal,
+            _ => ColumnType::Text,
+        }
+    }
+}
+
+// Shortens the name of table or index to max_len bytes, the truncated name gets the hash of the full name,
+// so long names with a common prefix do not share one table
+pub fn truncate_name(name: &str, max_len: usize) -> String {
+    if name.len() <= max_len {
+        return name.to_owned();
+    }
+    let mut end = max_len.saturating_sub(9);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}_{:08x}", &name[..end], fnv1a_hash(name) as u32)
+}
+
+// Maximum count of rows in one multi-row insert statement
+pub const INSERT_CHUNK_SIZE: usize = 500;
+
+pub struct PredicateRow {
+    pub doc_id: String,
+    pub doc_type: String,
+    pub created: Option<NaiveDateTime>,
+    pub value: SqlValue,
+    pub lang: String,
+    pub deleted: bool,
+}
+
+// Row of the history table: the value of predicate after the change, the value is Null if the predicate is removed
+pub struct HistoryRow {
+    pub row: PredicateRow,
+    pub op_id: Option<i64>,
+    pub date: Option<NaiveDateTime>,
+    pub update_counter: Option<i64>,
+}
+
+// Row of the class table: one column per predicate, the first value of predicate is stored in the column
+pub struct ClassRow {
+    pub doc_id: String,
+    pub created: Option<NaiveDateTime>,
+    pub deleted: bool,
+    pub values: Vec<(String, SqlValue)>,
+}
+
+pub fn resource_to_value(resource: &Resource) -> SqlValue {
+    match &resource.value {
+        Value::Bool(bool_value) => SqlValue::Bool(*bool_value),
+        Value::Int(int_value) => SqlValue::Int(*int_value),
+        Value::Str(str_value, _lang) => SqlValue::Str(str_value.to_owned()),
+        Value::Uri(uri_value) => SqlValue::Str(uri_value.to_owned()),
+        Value::Num(_m, _e) => SqlValue::Decimal(resource.get_float()),
+        Value::Datetime(timestamp) => SqlValue::Datetime(NaiveDateTime::from_timestamp(*timestamp, 0)),
+        _ => SqlValue::Null,
+    }
+}
+
+pub fn resource_lang(resource: &Resource) -> String {
+    if resource.get_lang().is_some() {
+        resource.get_lang().to_string().to_uppercase()
+    } else {
+        String::from("NO")
+    }
+}
+
+// Operations of the SQL server used by export, inserts and deletes are made between begin and commit/rollback.
+// There is no upsert by design: an individual has many rows in a predicate table and no unique key to upsert by,
+// so write_batch replaces its rows by delete and insert in one transaction, class rows are replaced the same way
+pub trait SqlDialect {
+    fn name(&self) -> &'static str;
+
+    // Name of table or column as it is stored by the server, the name is shortened to the length limit of identifiers
+    fn ident_name(&self, name: &str) -> String {
+        let mut end = name.len().min(64);
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name[..end].to_owned()
+    }
+
+    // Returns the columns of the tables of the current database: table -> column -> type, columns of unknown types are skipped
+    fn read_columns(&mut self) -> Result<HashMap<String, HashMap<String, ColumnType>>, &'static str>;
+
+    // Writes the statements changing the database to the file instead of executing them, the tables are still read from the database
+    fn set_dry_run(&mut self, _path: &str) -> Result<(), &'static str> {
+        error!("dry run is not supported by {}", self.name());
+        Err("dry run is not supported")
+    }
+
+    // Reports the given tables whose storage options differ from the configured ones, converts them if migrate is set
+    fn check_tables(&mut self, _tables: &HashSet<String>, _migrate: bool) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    fn begin(&mut self) -> Result<(), &'static str>;
+
+    fn commit(&mut self) -> Result<(), &'static str>;
+
+    fn rollback(&mut self) -> Result<(), &'static str>;
+
+    fn create_predicate_table(&mut self, table: &str, column_type: ColumnType) -> Result<(), &'static str>;
+
+    fn insert(&mut self, table: &str, rows: &[&PredicateRow]) -> Result<(), &'static str>;
+
+    // History table has the columns of the predicate table and op_id, date and update_counter of the change, rows are only appended
+    fn create_history_table(&mut self, table: &str, column_type: ColumnType) -> Result<(), &'static str>;
+
+    fn insert_history(&mut self, table: &str, rows: &[&HistoryRow]) -> Result<(), &'static str>;
+
+    fn create_class_table(&mut self, table: &str) -> Result<(), &'static str>;
+
+    fn add_column(&mut self, table: &str, column: &str, column_type: ColumnType) -> Result<(), &'static str>;
+
+    // Changes the type of the column to the wider one, the stored values are converted
+    fn widen_column(&mut self, table: &str, column: &str, from: ColumnType, to: ColumnType) -> Result<(), &'static str>;
+
+    fn insert_row(&mut self, table: &str, row: &ClassRow) -> Result<(), &'static str>;
+
+    // Returns doc_type, value as text and lang of the rows of individual in the predicate table
+    fn select_values(&mut self, table: &str, doc_id: &str) -> Result<Vec<(String, Option<String>, String)>, &'static str>;
+
+    // Returns the next page of distinct doc_id of the table greater than after, ordered by doc_id. The rows marked as deleted are skipped if skip_deleted is set
+    fn select_doc_ids(&mut self, table: &str, after: &str, limit: usize, skip_deleted: bool) -> Result<Vec<String>, &'static str>;
+
+    fn delete(&mut self, table: &str, doc_id: &str) -> Result<(), &'static str>;
+
+    fn mark_deleted(&mut self, table: &str, doc_id: &str) -> Result<(), &'static str>;
+}
+
+pub fn connect_to_sql(backend: &mut Backend, tries: i64, timeout: u64) -> Result<Box<dyn SqlDialect>, &'static str> {
+    if let Some(node) = backend.get_individual("cfg:standart_node", &mut Individual::default()) {
+        if let Some(v) = node.get_literals("v-s:push_individual_by_event") {
+            for el in v {
+                let mut connection = Individual::default();
+                if backend.storage.get_individual(&el, &mut connection) == ResultCode::Ok && !connection.is_exists_bool("v-s:deleted", true) {
+                    if let Some(transport) = connection.get_first_literal("v-s:transport") {
+                        match transport.as_str() {
+                            "mysql" => {
+                                info!("found configuration to connect to MySQL: {}", connection.get_id());
+                                return Ok(Box::new(MySqlDialect::connect(&mut connection)?));
+                            },
+                            "postgresql" => {
+                                info!("found configuration to connect to PostgreSQL: {}", connection.get_id());
+                                return Ok(Box::new(PostgreSqlDialect::connect(&mut connection)?));
+                            },
+                            "sqlite" => {
+                                info!("found configuration to connect to SQLite: {}", connection.get_id());
+                                return Ok(Box::new(SqliteDialect::connect(&mut connection)?));
+                            },
+                            _ => {},
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if tries != 0 {
+        let tries = tries - 1;
+        thread::sleep(time::Duration::from_millis(timeout));
+        error!("failed to find configuration, retry.");
+        connect_to_sql(backend, tries, timeout)
+    } else {
+        error!("failed to find configuration to connect to sql database");
+        Err("failed to find configuration to connect to sql database")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn widen_keeps_same_type() {
+        for column_type in [ColumnType::Bool, ColumnType::Integer, ColumnType::Decimal, ColumnType::Datetime, ColumnType::Uri, ColumnType::Text] {
+            assert_eq!(column_type.widen(column_type), column_type);
+        }
+    }
+
+    #[test]
+    fn widen_numbers() {
+        assert_eq!(ColumnType::Bool.widen(ColumnType::Integer), ColumnType::Integer);
+        assert_eq!(ColumnType::Integer.widen(ColumnType::Bool), ColumnType::Integer);
+        assert_eq!(ColumnType::Bool.widen(ColumnType::Decimal), ColumnType::Decimal);
+        assert_eq!(ColumnType::Integer.widen(ColumnType::Decimal), ColumnType::Decimal);
+        assert_eq!(ColumnType::Decimal.widen(ColumnType::Integer), ColumnType::Decimal);
+    }
+
+    #[test]
+    fn widen_other_types_to_text() {
+        assert_eq!(ColumnType::Uri.widen(ColumnType::Datetime), ColumnType::Text);
+        assert_eq!(ColumnType::Datetime.widen(ColumnType::Integer), ColumnType::Text);
+        assert_eq!(ColumnType::Bool.widen(ColumnType::Uri), ColumnType::Text);
+        assert_eq!(ColumnType::Text.widen(ColumnType::Decimal), ColumnType::Text);
+    }
+
+    #[test]
+    fn truncate_name_keeps_short_names() {
+        assert_eq!(truncate_name("v-s:created", 63), "v-s:created");
+        let name = "a".repeat(63);
+        assert_eq!(truncate_name(&name, 63), name);
+    }
+
+    #[test]
+    fn truncate_name_tells_apart_long_names_with_common_prefix() {
+        let prefix = "v-s:".to_owned() + &"x".repeat(70);
+        let first = truncate_name(&format!("{}first", prefix), 63);
+        let second = truncate_name(&format!("{}second", prefix), 63);
+        assert_eq!(first.len(), 63);
+        assert_eq!(second.len(), 63);
+        assert_ne!(first, second);
+        assert_eq!(first, truncate_name(&format!("{}first", prefix), 63));
+    }
+
+    #[test]
+    fn truncate_name_cuts_at_char_boundary() {
+        let name = "я".repeat(40);
+        let truncated = truncate_name(&name, 63);
+        assert!(truncated.len() <= 63);
+        assert!(truncated.starts_with("яя"));
+    }
+}
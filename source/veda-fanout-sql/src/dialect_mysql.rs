@@ -0,0 +1,571 @@
+use crate::dialect::{ClassRow, ColumnType, HistoryRow, PredicateRow, SqlDialect, SqlValue, INSERT_CHUNK_SIZE};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use v_common::onto::individual::Individual;
+
+// Prepared statements are cached by the connection: delete and update of every table, the inserts of the fixed chunk sizes
+// and the insert into the class table, its row has a value for every column, so the statement changes only when a column is added
+const STMT_CACHE_SIZE: usize = 4096;
+
+pub struct MySqlDialect {
+    pool: mysql::Pool,
+    conn: Option<mysql::PooledConn>,
+    engine: String,
+    charset: String,
+    collation: String,
+    // Statements are written to the file instead of the database
+    dry_run: Option<File>,
+}
+
+impl MySqlDialect {
+    pub fn connect(connection: &mut Individual) -> Result<Self, &'static str> {
+        let host = connection.get_first_literal("v-s:host").unwrap_or_default();
+        let port = connection.get_first_integer("v-s:port").unwrap_or(3306) as u16;
+        let login = connection.get_first_literal("v-s:login").unwrap();
+        let pass = connection.get_first_literal("v-s:password").unwrap();
+        let db = connection.get_first_literal("v-s:sql_database").unwrap();
+        let engine = connection.get_first_literal("v-s:sql_engine").unwrap_or_else(|| "InnoDB".to_owned());
+        let charset = connection.get_first_literal("v-s:sql_charset").unwrap_or_else(|| "utf8mb4".to_owned());
+        let collation = connection.get_first_literal("v-s:sql_collation").unwrap_or_else(|| format!("{}_bin", charset));
+        info!("trying to connect to mysql, host: {}, port: {}, login: {}, pass: {}, db: {}", host, port, login, pass, db);
+        info!("table options: engine = {}, charset = {}, collation = {}", engine, charset, collation);
+        if !engine.eq_ignore_ascii_case("InnoDB") {
+            warn!("engine {} may not support transactions, a failed export can leave partially written rows", engine);
+        }
+        let mut builder = mysql::OptsBuilder::new();
+        builder.ip_or_hostname(Some(host)).tcp_port(port).user(Some(login)).pass(Some(pass)).db_name(Some(db)).stmt_cache_size(STMT_CACHE_SIZE);
+        let opts: mysql::Opts = builder.into();
+        match mysql::Pool::new(opts) {
+            Ok(pool) => {
+                info!("connection to MySQL established successfully");
+                Ok(MySqlDialect {
+                    pool,
+                    conn: None,
+                    engine,
+                    charset,
+                    collation,
+                    dry_run: None,
+                })
+            },
+            Err(e) => {
+                error!("failed to connect to MySQL, err = {}", e);
+                Err("failed to connect to MySQL")
+            },
+        }
+    }
+
+    fn conn(&mut self) -> Result<&mut mysql::PooledConn, String> {
+        if self.conn.is_none() {
+            self.conn = Some(self.pool.get_conn().map_err(|e| e.to_string())?);
+        }
+        Ok(self.conn.as_mut().unwrap())
+    }
+
+    fn query(&mut self, query: &str) -> Result<(), String> {
+        if let Some(file) = self.dry_run.as_mut() {
+            return write_statement(file, query);
+        }
+        self.conn()?.query(query).map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    fn exec(&mut self, query: &str, params: Vec<mysql::Value>) -> Result<(), String> {
+        if let Some(file) = self.dry_run.as_mut() {
+            return write_statement(file, &inline_params(query, &params));
+        }
+        self.conn()?.prep_exec(query, params).map(|_| ()).map_err(|e| e.to_string())
+    }
+}
+
+// Splits the rows into chunks of fixed sizes: full chunks, then the rest by powers of two,
+// so the inserts into one table take a few places in the statement cache
+fn insert_chunks<T>(rows: &[T]) -> Vec<&[T]> {
+    let mut chunks = vec![];
+    let mut rest = rows;
+    while !rest.is_empty() {
+        let mut size = INSERT_CHUNK_SIZE;
+        while size > rest.len() {
+            size = size.next_power_of_two() / 2;
+        }
+        let (chunk, tail) = rest.split_at(size);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks
+}
+
+// The values of parameters are written as literals, so the statement of the dry run may be executed as is.
+// It is used only for the dry run, statements sent to the server always have bound parameters
+fn inline_params(query: &str, params: &[mysql::Value]) -> String {
+    let mut result = String::with_capacity(query.len());
+    let mut params = params.iter();
+    let mut is_quoted = false;
+    for c in query.chars() {
+        match c {
+            '`' => {
+                is_quoted = !is_quoted;
+                result.push(c);
+            },
+            '?' if !is_quoted => match params.next() {
+                Some(param) => result.push_str(&param.as_sql(false)),
+                None => result.push(c),
+            },
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+fn write_statement(file: &mut File, query: &str) -> Result<(), String> {
+    writeln!(file, "{};", query.trim_end().trim_end_matches(';')).map_err(|e| e.to_string())
+}
+
+fn quote_ident(name: &str) -> String {
+    format!("`{}`", name.replace('`', "``"))
+}
+
+fn sql_type(column_type: ColumnType) -> &'static str {
+    match column_type {
+        ColumnType::Bool => "BOOL",
+        ColumnType::Datetime => "DATETIME",
+        ColumnType::Decimal => "DECIMAL (14,4)",
+        ColumnType::Integer => "INTEGER",
+        ColumnType::Text => "TEXT",
+        ColumnType::Uri => "CHAR(128)",
+    }
+}
+
+fn parse_column_type(data_type: &str) -> Option<ColumnType> {
+    match data_type {
+        "tinyint" => Some(ColumnType::Bool),
+        "int" | "bigint" => Some(ColumnType::Integer),
+        "decimal" | "double" => Some(ColumnType::Decimal),
+        "datetime" => Some(ColumnType::Datetime),
+        "char" | "varchar" => Some(ColumnType::Uri),
+        "text" | "mediumtext" | "longtext" => Some(ColumnType::Text),
+        _ => None,
+    }
+}
+
+fn to_mysql_value(value: &SqlValue) -> mysql::Value {
+    match value {
+        SqlValue::Null => mysql::Value::NULL,
+        SqlValue::Bool(bool_value) => mysql::Value::Int(*bool_value as i64),
+        SqlValue::Int(int_value) => mysql::Value::Int(*int_value),
+        SqlValue::Decimal(num_value) => mysql::Value::Float(*num_value),
+        SqlValue::Str(str_value) => mysql::Value::from(str_value.as_str()),
+        SqlValue::Datetime(datetime) => mysql::Value::from(*datetime),
+    }
+}
+
+impl SqlDialect for MySqlDialect {
+    fn name(&self) -> &'static str {
+        "mysql"
+    }
+
+    fn set_dry_run(&mut self, path: &str) -> Result<(), &'static str> {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => {
+                info!("dry run, statements are written to {}", path);
+                self.dry_run = Some(file);
+                Ok(())
+            },
+            Err(e) => {
+                error!("failed to open dry run file {}, err = {:?}", path, e);
+                Err("failed to open dry run file")
+            },
+        }
+    }
+
+    fn check_tables(&mut self, tables: &HashSet<String>, migrate: bool) -> Result<(), &'static str> {
+        let mut outdated = vec![];
+        match self.pool.prep_exec("SELECT TABLE_NAME, ENGINE, TABLE_COLLATION FROM information_schema.tables WHERE TABLE_SCHEMA = DATABASE();", ()) {
+            Ok(result) => {
+                for row in result {
+                    let (name, engine, collation): (String, Option<String>, Option<String>) = match row {
+                        Ok(row) => mysql::from_row(row),
+                        Err(e) => {
+                            error!("failed to read table options, err = {}", e);
+                            return Err("failed to read table options");
+                        },
+                    };
+                    if !tables.contains(&name) {
+                        continue;
+                    }
+                    let engine = engine.unwrap_or_default();
+                    let collation = collation.unwrap_or_default();
+                    if !engine.eq_ignore_ascii_case(&self.engine) || !collation.eq_ignore_ascii_case(&self.collation) {
+                        outdated.push((name, engine, collation));
+                    }
+                }
+            },
+            Err(e) => {
+                error!("failed to read table options, err = {}", e);
+                return Err("failed to read table options");
+            },
+        }
+
+        if outdated.is_empty() {
+            return Ok(());
+        }
+
+        if !migrate {
+            for (name, engine, collation) in outdated.iter() {
+                warn!("table {} uses engine = {}, collation = {}, expected engine = {}, collation = {}", name, engine, collation, self.engine, self.collation);
+            }
+            warn!("found {} tables with outdated options, run with --migrate_engine to convert them", outdated.len());
+            return Ok(());
+        }
+
+        for (name, engine, collation) in outdated.iter() {
+            info!("convert table {}, engine = {} -> {}, collation = {} -> {}", name, engine, self.engine, collation, self.collation);
+            let query = format!("ALTER TABLE {} ENGINE={}, CONVERT TO CHARACTER SET {} COLLATE {};", quote_ident(name), self.engine, self.charset, self.collation);
+            if let Err(e) = self.query(&query) {
+                error!("failed to convert table {}, err = {}", name, e);
+                return Err("failed to convert table");
+            }
+        }
+        info!("converted {} tables", outdated.len());
+        Ok(())
+    }
+
+    fn read_columns(&mut self) -> Result<HashMap<String, HashMap<String, ColumnType>>, &'static str> {
+        let mut tables: HashMap<String, HashMap<String, ColumnType>> = HashMap::new();
+        match self.pool.prep_exec("SELECT TABLE_NAME, COLUMN_NAME, DATA_TYPE FROM information_schema.columns WHERE TABLE_SCHEMA = DATABASE();", ()) {
+            Ok(result) => {
+                for row in result {
+                    let (table, column, data_type): (String, String, String) = match row {
+                        Ok(row) => mysql::from_row(row),
+                        Err(e) => {
+                            error!("failed to read table columns, err = {}", e);
+                            return Err("failed to read table columns");
+                        },
+                    };
+                    let columns = tables.entry(table).or_default();
+                    if let Some(column_type) = parse_column_type(&data_type.to_lowercase()) {
+                        columns.insert(column, column_type);
+                    }
+                }
+                debug!("Existing tables: {:?}", tables);
+                Ok(tables)
+            },
+            Err(e) => {
+                error!("failed to read table columns, err = {}", e);
+                Err("failed to read table columns")
+            },
+        }
+    }
+
+    fn begin(&mut self) -> Result<(), &'static str> {
+        let res = self.query("SET TRANSACTION ISOLATION LEVEL READ COMMITTED").and_then(|_| self.query("START TRANSACTION WITH CONSISTENT SNAPSHOT"));
+        if let Err(e) = res {
+            error!("failed to start transaction, err = {}", e);
+            self.conn = None;
+            return Err("failed to start transaction");
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), &'static str> {
+        if let Err(e) = self.query("COMMIT") {
+            error!("failed to commit transaction, err = {}", e);
+            self.conn = None;
+            return Err("failed to commit transaction");
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), &'static str> {
+        if let Err(e) = self.query("ROLLBACK") {
+            error!("failed to roll back transaction, err = {}", e);
+            self.conn = None;
+            return Err("failed to roll back transaction");
+        }
+        Ok(())
+    }
+
+    fn create_predicate_table(&mut self, table: &str, column_type: ColumnType) -> Result<(), &'static str> {
+        let sql_value_index = if column_type == ColumnType::Text {
+            ""
+        } else {
+            ", INDEX civ(`value`)"
+        };
+        let query = format!(
+            "CREATE TABLE {} ( \
+             `ID` BIGINT NOT NULL AUTO_INCREMENT, \
+             `doc_id` CHAR(128) NOT NULL, \
+             `doc_type` CHAR(128) NOT NULL, \
+             `created` DATETIME NULL, \
+             `value` {} NULL, \
+             `lang` CHAR(2) NULL, \
+             `deleted` BOOL NULL, \
+             PRIMARY KEY (`ID`), \
+             INDEX c1(`doc_id`), INDEX c2(`doc_type`), INDEX c3 (`created`), INDEX c4(`lang`) {} \
+             ) ENGINE={} DEFAULT CHARSET={} COLLATE={};",
+            quote_ident(table),
+            sql_type(column_type),
+            sql_value_index,
+            self.engine,
+            self.charset,
+            self.collation
+        );
+
+        if let Err(e) = self.query(&query) {
+            error!("failed to create property table, err = {}", e);
+            return Err("failed to create property table");
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, table: &str, rows: &[&PredicateRow]) -> Result<(), &'static str> {
+        for chunk in insert_chunks(rows) {
+            let query = format!(
+                "INSERT INTO {} (doc_id, doc_type, created, value, lang, deleted) VALUES {}",
+                quote_ident(table),
+                vec!["(?, ?, ?, ?, ?, ?)"; chunk.len()].join(", ")
+            );
+            let mut params = Vec::with_capacity(chunk.len() * 6);
+            for row in chunk {
+                params.push(mysql::Value::from(row.doc_id.as_str()));
+                params.push(mysql::Value::from(row.doc_type.as_str()));
+                params.push(row.created.map_or(mysql::Value::NULL, mysql::Value::from));
+                params.push(to_mysql_value(&row.value));
+                params.push(mysql::Value::from(row.lang.as_str()));
+                params.push(if row.deleted {
+                    mysql::Value::Int(1)
+                } else {
+                    mysql::Value::NULL
+                });
+            }
+            if let Err(e) = self.exec(&query, params) {
+                error!("failed to insert individuals, count = {}, property table = {}, err = {}", chunk.len(), table, e);
+                return Err("failed to insert individuals");
+            }
+        }
+        Ok(())
+    }
+
+    fn create_history_table(&mut self, table: &str, column_type: ColumnType) -> Result<(), &'static str> {
+        let sql_value_index = if column_type == ColumnType::Text {
+            ""
+        } else {
+            ", INDEX civ(`value`)"
+        };
+        let query = format!(
+            "CREATE TABLE IF NOT EXISTS {} ( \
+             `ID` BIGINT NOT NULL AUTO_INCREMENT, \
+             `doc_id` CHAR(128) NOT NULL, \
+             `doc_type` CHAR(128) NOT NULL, \
+             `created` DATETIME NULL, \
+             `value` {} NULL, \
+             `lang` CHAR(2) NULL, \
+             `deleted` BOOL NULL, \
+             `op_id` BIGINT NULL, \
+             `date` DATETIME NULL, \
+             `update_counter` BIGINT NULL, \
+             PRIMARY KEY (`ID`), \
+             INDEX c1(`doc_id`, `date`), INDEX c2(`doc_type`), INDEX c5(`op_id`) {} \
+             ) ENGINE={} DEFAULT CHARSET={} COLLATE={};",
+            quote_ident(table),
+            sql_type(column_type),
+            sql_value_index,
+            self.engine,
+            self.charset,
+            self.collation
+        );
+
+        if let Err(e) = self.query(&query) {
+            error!("failed to create history table, err = {}", e);
+            return Err("failed to create history table");
+        }
+        Ok(())
+    }
+
+    fn insert_history(&mut self, table: &str, rows: &[&HistoryRow]) -> Result<(), &'static str> {
+        for chunk in insert_chunks(rows) {
+            let query = format!(
+                "INSERT INTO {} (doc_id, doc_type, created, value, lang, deleted, op_id, date, update_counter) VALUES {}",
+                quote_ident(table),
+                vec!["(?, ?, ?, ?, ?, ?, ?, ?, ?)"; chunk.len()].join(", ")
+            );
+            let mut params = Vec::with_capacity(chunk.len() * 9);
+            for history in chunk {
+                let row = &history.row;
+                params.push(mysql::Value::from(row.doc_id.as_str()));
+                params.push(mysql::Value::from(row.doc_type.as_str()));
+                params.push(row.created.map_or(mysql::Value::NULL, mysql::Value::from));
+                params.push(to_mysql_value(&row.value));
+                params.push(mysql::Value::from(row.lang.as_str()));
+                params.push(if row.deleted {
+                    mysql::Value::Int(1)
+                } else {
+                    mysql::Value::NULL
+                });
+                params.push(history.op_id.map_or(mysql::Value::NULL, mysql::Value::from));
+                params.push(history.date.map_or(mysql::Value::NULL, mysql::Value::from));
+                params.push(history.update_counter.map_or(mysql::Value::NULL, mysql::Value::from));
+            }
+            if let Err(e) = self.exec(&query, params) {
+                error!("failed to insert history, count = {}, history table = {}, err = {}", chunk.len(), table, e);
+                return Err("failed to insert history");
+            }
+        }
+        Ok(())
+    }
+
+    fn create_class_table(&mut self, table: &str) -> Result<(), &'static str> {
+        let query = format!(
+            "CREATE TABLE IF NOT EXISTS {} ( \
+             `doc_id` CHAR(128) NOT NULL, \
+             `created` DATETIME NULL, \
+             `deleted` BOOL NULL, \
+             PRIMARY KEY (`doc_id`), \
+             INDEX c3 (`created`) \
+             ) ENGINE={} DEFAULT CHARSET={} COLLATE={};",
+            quote_ident(table),
+            self.engine,
+            self.charset,
+            self.collation
+        );
+        if let Err(e) = self.query(&query) {
+            error!("failed to create class table, err = {}", e);
+            return Err("failed to create class table");
+        }
+        Ok(())
+    }
+
+    fn add_column(&mut self, table: &str, column: &str, column_type: ColumnType) -> Result<(), &'static str> {
+        let query = format!("ALTER TABLE {} ADD COLUMN {} {} NULL;", quote_ident(table), quote_ident(column), sql_type(column_type));
+        if let Err(e) = self.query(&query) {
+            error!("failed to add column {} to table {}, err = {}", column, table, e);
+            return Err("failed to add column");
+        }
+        Ok(())
+    }
+
+    fn widen_column(&mut self, table: &str, column: &str, from: ColumnType, to: ColumnType) -> Result<(), &'static str> {
+        // TEXT can not be indexed without a prefix length, so the value index of the property table is dropped
+        let drop_index = if to == ColumnType::Text && from != ColumnType::Text && column == "value" {
+            "DROP INDEX civ, "
+        } else {
+            ""
+        };
+        let query = format!("ALTER TABLE {} {}MODIFY {} {} NULL;", quote_ident(table), drop_index, quote_ident(column), sql_type(to));
+        if let Err(e) = self.query(&query) {
+            error!("failed to change type of column {} of table {} from {:?} to {:?}, err = {}", column, table, from, to, e);
+            return Err("failed to change type of column");
+        }
+        Ok(())
+    }
+
+    fn insert_row(&mut self, table: &str, row: &ClassRow) -> Result<(), &'static str> {
+        let mut columns = vec!["doc_id".to_owned(), "created".to_owned(), "deleted".to_owned()];
+        let mut params = vec![
+            mysql::Value::from(row.doc_id.as_str()),
+            row.created.map_or(mysql::Value::NULL, mysql::Value::from),
+            if row.deleted {
+                mysql::Value::Int(1)
+            } else {
+                mysql::Value::NULL
+            },
+        ];
+        for (column, value) in row.values.iter() {
+            columns.push(quote_ident(column));
+            params.push(to_mysql_value(value));
+        }
+        let query = format!("INSERT INTO {} ({}) VALUES ({})", quote_ident(table), columns.join(", "), vec!["?"; columns.len()].join(", "));
+        if let Err(e) = self.exec(&query, params) {
+            error!("failed to insert individual, uri = {}, class table = {}, err = {}", row.doc_id, table, e);
+            return Err("failed to insert individual");
+        }
+        Ok(())
+    }
+
+    fn select_values(&mut self, table: &str, doc_id: &str) -> Result<Vec<(String, Option<String>, String)>, &'static str> {
+        let query = format!("SELECT doc_type, CAST(value AS CHAR), lang FROM {} WHERE doc_id = ?", quote_ident(table));
+        let res = self.pool.prep_exec(query, (doc_id,)).and_then(|result| {
+            result
+                .map(|row| {
+                    row.map(|row| {
+                        let (doc_type, value, lang): (String, Option<String>, Option<String>) = mysql::from_row(row);
+                        (doc_type, value, lang.unwrap_or_default())
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()
+        });
+        match res {
+            Ok(rows) => Ok(rows),
+            Err(e) => {
+                error!("failed to read individual, uri = {}, property table = {}, err = {}", doc_id, table, e);
+                Err("failed to read individual")
+            },
+        }
+    }
+
+    fn select_doc_ids(&mut self, table: &str, after: &str, limit: usize, skip_deleted: bool) -> Result<Vec<String>, &'static str> {
+        let deleted_clause = if skip_deleted {
+            " AND deleted IS NULL"
+        } else {
+            ""
+        };
+        let query = format!("SELECT DISTINCT doc_id FROM {} WHERE doc_id > ?{} ORDER BY doc_id LIMIT {}", quote_ident(table), deleted_clause, limit);
+        let res = self.pool.prep_exec(query, (after,)).and_then(|result| result.map(|row| row.map(mysql::from_row::<String>)).collect::<Result<Vec<_>, _>>());
+        match res {
+            Ok(doc_ids) => Ok(doc_ids),
+            Err(e) => {
+                error!("failed to read individuals of property table {}, err = {}", table, e);
+                Err("failed to read individuals")
+            },
+        }
+    }
+
+    fn delete(&mut self, table: &str, doc_id: &str) -> Result<(), &'static str> {
+        let query = format!("DELETE FROM {} WHERE doc_id = ?", quote_ident(table));
+        if let Err(e) = self.exec(&query, vec![mysql::Value::from(doc_id)]) {
+            error!("failed to delete individual, uri = {}, property table = {}, err = {}", doc_id, table, e);
+            return Err("failed to delete individual");
+        }
+        Ok(())
+    }
+
+    fn mark_deleted(&mut self, table: &str, doc_id: &str) -> Result<(), &'static str> {
+        let query = format!("UPDATE {} SET deleted = 1 WHERE doc_id = ?", quote_ident(table));
+        if let Err(e) = self.exec(&query, vec![mysql::Value::from(doc_id)]) {
+            error!("failed to mark individual as deleted, uri = {}, property table = {}, err = {}", doc_id, table, e);
+            return Err("failed to mark individual as deleted");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inline_params_replaces_placeholders() {
+        let query = "INSERT INTO `v-s:created` (doc_id, value, lang) VALUES (?, ?, ?)";
+        let params = vec![mysql::Value::from("d:doc"), mysql::Value::Int(42), mysql::Value::NULL];
+        assert_eq!(inline_params(query, &params), "INSERT INTO `v-s:created` (doc_id, value, lang) VALUES ('d:doc', 42, NULL)");
+    }
+
+    #[test]
+    fn inline_params_skips_quoted_identifiers() {
+        let query = "DELETE FROM `what?` WHERE doc_id = ?";
+        assert_eq!(inline_params(query, &[mysql::Value::from("d:doc")]), "DELETE FROM `what?` WHERE doc_id = 'd:doc'");
+    }
+
+    #[test]
+    fn insert_chunks_use_fixed_sizes() {
+        let rows: Vec<usize> = (0..INSERT_CHUNK_SIZE + 300).collect();
+        let sizes: Vec<usize> = insert_chunks(&rows).iter().map(|chunk| chunk.len()).collect();
+        assert_eq!(sizes, vec![INSERT_CHUNK_SIZE, 256, 32, 8, 4]);
+        assert_eq!(insert_chunks(&rows[..1]).len(), 1);
+        assert!(insert_chunks::<usize>(&[]).is_empty());
+    }
+
+    #[test]
+    fn inline_params_keeps_placeholders_without_params() {
+        assert_eq!(inline_params("SELECT ?, ?", &[mysql::Value::Int(1)]), "SELECT 1, ?");
+    }
+}
@@ -0,0 +1,449 @@
+use crate::dialect::{truncate_name, ClassRow, ColumnType, HistoryRow, PredicateRow, SqlDialect, SqlValue, INSERT_CHUNK_SIZE};
+use bytes::BytesMut;
+use postgres::types::{to_sql_checked, IsNull, ToSql, Type};
+use std::collections::HashMap;
+use std::error::Error;
+use v_common::onto::individual::Individual;
+
+// PostgreSQL silently truncates longer identifiers
+const MAX_IDENT_LEN: usize = 63;
+
+pub struct PostgreSqlDialect {
+    config: postgres::Config,
+    client: postgres::Client,
+    statements: HashMap<String, postgres::Statement>,
+}
+
+impl PostgreSqlDialect {
+    pub fn connect(connection: &mut Individual) -> Result<Self, &'static str> {
+        let host = connection.get_first_literal("v-s:host").unwrap_or_default();
+        let port = connection.get_first_integer("v-s:port").unwrap_or(5432) as u16;
+        let login = connection.get_first_literal("v-s:login").unwrap();
+        let pass = connection.get_first_literal("v-s:password").unwrap();
+        let db = connection.get_first_literal("v-s:sql_database").unwrap();
+        info!("trying to connect to postgresql, host: {}, port: {}, login: {}, pass: {}, db: {}", host, port, login, pass, db);
+        let mut config = postgres::Config::new();
+        config.host(&host).port(port).user(&login).password(&pass).dbname(&db);
+        match config.connect(postgres::NoTls) {
+            Ok(client) => {
+                info!("connection to PostgreSQL established successfully");
+                Ok(PostgreSqlDialect {
+                    config,
+                    client,
+                    statements: HashMap::new(),
+                })
+            },
+            Err(e) => {
+                error!("failed to connect to PostgreSQL, err = {}", e);
+                Err("failed to connect to PostgreSQL")
+            },
+        }
+    }
+
+    // The connection is opened again after it is closed by the server, the prepared statements of the closed connection are dropped
+    fn check_connection(&mut self) -> Result<(), &'static str> {
+        if !self.client.is_closed() {
+            return Ok(());
+        }
+        warn!("connection to PostgreSQL is closed, reconnect");
+        match self.config.connect(postgres::NoTls) {
+            Ok(client) => {
+                info!("connection to PostgreSQL established successfully");
+                self.client = client;
+                self.statements.clear();
+                Ok(())
+            },
+            Err(e) => {
+                error!("failed to connect to PostgreSQL, err = {}", e);
+                Err("failed to connect to PostgreSQL")
+            },
+        }
+    }
+
+    fn prepare(&mut self, query: String) -> Result<postgres::Statement, postgres::Error> {
+        if let Some(stmt) = self.statements.get(&query) {
+            return Ok(stmt.clone());
+        }
+        let stmt = self.client.prepare(&query)?;
+        self.statements.insert(query, stmt.clone());
+        Ok(stmt)
+    }
+}
+
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+// Index names are local to the schema in PostgreSQL, so they are prefixed by the table name
+fn index_ident(table: &str, suffix: &str) -> String {
+    quote_ident(&truncate_name(&format!("{}_{}", table, suffix), MAX_IDENT_LEN))
+}
+
+fn sql_type(column_type: ColumnType) -> &'static str {
+    match column_type {
+        ColumnType::Bool => "BOOLEAN",
+        ColumnType::Datetime => "TIMESTAMP",
+        ColumnType::Decimal => "DOUBLE PRECISION",
+        ColumnType::Integer => "BIGINT",
+        ColumnType::Text => "TEXT",
+        ColumnType::Uri => "VARCHAR(128)",
+    }
+}
+
+fn parse_column_type(data_type: &str) -> Option<ColumnType> {
+    match data_type {
+        "boolean" => Some(ColumnType::Bool),
+        "integer" | "bigint" => Some(ColumnType::Integer),
+        "double precision" | "numeric" => Some(ColumnType::Decimal),
+        "timestamp without time zone" => Some(ColumnType::Datetime),
+        "character" | "character varying" => Some(ColumnType::Uri),
+        "text" => Some(ColumnType::Text),
+        _ => None,
+    }
+}
+
+impl ToSql for SqlValue {
+    fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        match self {
+            SqlValue::Null => Ok(IsNull::Yes),
+            SqlValue::Str(str_value) => str_value.to_sql_checked(ty, out),
+            // Values written to a widened column are converted to the type of the column
+            _ if *ty == Type::TEXT || *ty == Type::VARCHAR || *ty == Type::BPCHAR => self.to_text().to_sql_checked(ty, out),
+            SqlValue::Bool(bool_value) if *ty == Type::INT8 => (*bool_value as i64).to_sql_checked(ty, out),
+            SqlValue::Bool(bool_value) if *ty == Type::FLOAT8 => (*bool_value as i64 as f64).to_sql_checked(ty, out),
+            SqlValue::Int(int_value) if *ty == Type::FLOAT8 => (*int_value as f64).to_sql_checked(ty, out),
+            SqlValue::Bool(bool_value) => bool_value.to_sql_checked(ty, out),
+            SqlValue::Int(int_value) => int_value.to_sql_checked(ty, out),
+            SqlValue::Decimal(num_value) => num_value.to_sql_checked(ty, out),
+            SqlValue::Datetime(datetime) => datetime.to_sql_checked(ty, out),
+        }
+    }
+
+    // The type of the value is checked by to_sql of the wrapped value
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+
+    to_sql_checked!();
+}
+
+impl SqlDialect for PostgreSqlDialect {
+    fn name(&self) -> &'static str {
+        "postgresql"
+    }
+
+    fn ident_name(&self, name: &str) -> String {
+        truncate_name(name, MAX_IDENT_LEN)
+    }
+
+    fn read_columns(&mut self) -> Result<HashMap<String, HashMap<String, ColumnType>>, &'static str> {
+        let mut tables: HashMap<String, HashMap<String, ColumnType>> = HashMap::new();
+        match self.client.query("SELECT table_name::text, column_name::text, data_type::text FROM information_schema.columns WHERE table_schema = current_schema()", &[])
+        {
+            Ok(rows) => {
+                for row in rows {
+                    let table: String = row.get(0);
+                    let column: String = row.get(1);
+                    let data_type: String = row.get(2);
+                    let columns = tables.entry(table).or_default();
+                    if let Some(column_type) = parse_column_type(&data_type.to_lowercase()) {
+                        columns.insert(column, column_type);
+                    }
+                }
+                debug!("Existing tables: {:?}", tables);
+                Ok(tables)
+            },
+            Err(e) => {
+                error!("failed to read table columns, err = {}", e);
+                Err("failed to read table columns")
+            },
+        }
+    }
+
+    fn begin(&mut self) -> Result<(), &'static str> {
+        self.check_connection()?;
+        if let Err(e) = self.client.batch_execute("BEGIN ISOLATION LEVEL READ COMMITTED") {
+            error!("failed to start transaction, err = {}", e);
+            return Err("failed to start transaction");
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), &'static str> {
+        if let Err(e) = self.client.batch_execute("COMMIT") {
+            error!("failed to commit transaction, err = {}", e);
+            return Err("failed to commit transaction");
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), &'static str> {
+        if let Err(e) = self.client.batch_execute("ROLLBACK") {
+            error!("failed to roll back transaction, err = {}", e);
+            return Err("failed to roll back transaction");
+        }
+        Ok(())
+    }
+
+    fn create_predicate_table(&mut self, table: &str, column_type: ColumnType) -> Result<(), &'static str> {
+        let mut query = format!(
+            "CREATE TABLE IF NOT EXISTS {0} ( \
+             \"ID\" BIGSERIAL PRIMARY KEY, \
+             \"doc_id\" VARCHAR(128) NOT NULL, \
+             \"doc_type\" VARCHAR(128) NOT NULL, \
+             \"created\" TIMESTAMP NULL, \
+             \"value\" {1} NULL, \
+             \"lang\" CHAR(2) NULL, \
+             \"deleted\" BOOLEAN NULL); \
+             CREATE INDEX IF NOT EXISTS {2} ON {0} (\"doc_id\"); \
+             CREATE INDEX IF NOT EXISTS {3} ON {0} (\"doc_type\"); \
+             CREATE INDEX IF NOT EXISTS {4} ON {0} (\"created\"); \
+             CREATE INDEX IF NOT EXISTS {5} ON {0} (\"lang\");",
+            quote_ident(table),
+            sql_type(column_type),
+            index_ident(table, "c1"),
+            index_ident(table, "c2"),
+            index_ident(table, "c3"),
+            index_ident(table, "c4")
+        );
+        if column_type != ColumnType::Text {
+            query.push_str(&format!(" CREATE INDEX IF NOT EXISTS {} ON {} (\"value\");", index_ident(table, "civ"), quote_ident(table)));
+        }
+
+        if let Err(e) = self.client.batch_execute(&query) {
+            error!("failed to create property table, err = {}", e);
+            return Err("failed to create property table");
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, table: &str, rows: &[&PredicateRow]) -> Result<(), &'static str> {
+        for chunk in rows.chunks(INSERT_CHUNK_SIZE) {
+            let values: Vec<String> = (0..chunk.len())
+                .map(|idx| format!("(${}, ${}, ${}, ${}, ${}, ${})", idx * 6 + 1, idx * 6 + 2, idx * 6 + 3, idx * 6 + 4, idx * 6 + 5, idx * 6 + 6))
+                .collect();
+            let query = format!("INSERT INTO {} (doc_id, doc_type, created, value, lang, deleted) VALUES {}", quote_ident(table), values.join(", "));
+            let deleted: Vec<Option<bool>> = chunk
+                .iter()
+                .map(|row| {
+                    if row.deleted {
+                        Some(true)
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            let mut params: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(chunk.len() * 6);
+            for (row, row_deleted) in chunk.iter().zip(deleted.iter()) {
+                params.push(&row.doc_id);
+                params.push(&row.doc_type);
+                params.push(&row.created);
+                params.push(&row.value);
+                params.push(&row.lang);
+                params.push(row_deleted);
+            }
+            // Only statements of the full chunk size are kept prepared, the tail of the rows uses an unnamed statement
+            let res = if chunk.len() == INSERT_CHUNK_SIZE {
+                self.prepare(query).and_then(|stmt| self.client.execute(&stmt, &params))
+            } else {
+                self.client.execute(query.as_str(), &params)
+            };
+            if let Err(e) = res {
+                error!("failed to insert individuals, count = {}, property table = {}, err = {}", chunk.len(), table, e);
+                return Err("failed to insert individuals");
+            }
+        }
+        Ok(())
+    }
+
+    fn create_history_table(&mut self, table: &str, column_type: ColumnType) -> Result<(), &'static str> {
+        let mut query = format!(
+            "CREATE TABLE IF NOT EXISTS {0} ( \
+             \"ID\" BIGSERIAL PRIMARY KEY, \
+             \"doc_id\" VARCHAR(128) NOT NULL, \
+             \"doc_type\" VARCHAR(128) NOT NULL, \
+             \"created\" TIMESTAMP NULL, \
+             \"value\" {1} NULL, \
+             \"lang\" CHAR(2) NULL, \
+             \"deleted\" BOOLEAN NULL, \
+             \"op_id\" BIGINT NULL, \
+             \"date\" TIMESTAMP NULL, \
+             \"update_counter\" BIGINT NULL); \
+             CREATE INDEX IF NOT EXISTS {2} ON {0} (\"doc_id\", \"date\"); \
+             CREATE INDEX IF NOT EXISTS {3} ON {0} (\"doc_type\"); \
+             CREATE INDEX IF NOT EXISTS {4} ON {0} (\"op_id\");",
+            quote_ident(table),
+            sql_type(column_type),
+            index_ident(table, "c1"),
+            index_ident(table, "c2"),
+            index_ident(table, "c5")
+        );
+        if column_type != ColumnType::Text {
+            query.push_str(&format!(" CREATE INDEX IF NOT EXISTS {} ON {} (\"value\");", index_ident(table, "civ"), quote_ident(table)));
+        }
+
+        if let Err(e) = self.client.batch_execute(&query) {
+            error!("failed to create history table, err = {}", e);
+            return Err("failed to create history table");
+        }
+        Ok(())
+    }
+
+    fn insert_history(&mut self, table: &str, rows: &[&HistoryRow]) -> Result<(), &'static str> {
+        for chunk in rows.chunks(INSERT_CHUNK_SIZE) {
+            let values: Vec<String> =
+                (0..chunk.len()).map(|idx| format!("({})", (1..=9).map(|col| format!("${}", idx * 9 + col)).collect::<Vec<String>>().join(", "))).collect();
+            let query =
+                format!("INSERT INTO {} (doc_id, doc_type, created, value, lang, deleted, op_id, date, update_counter) VALUES {}", quote_ident(table), values.join(", "));
+            let deleted: Vec<Option<bool>> = chunk
+                .iter()
+                .map(|history| {
+                    if history.row.deleted {
+                        Some(true)
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            let mut params: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(chunk.len() * 9);
+            for (history, row_deleted) in chunk.iter().zip(deleted.iter()) {
+                params.push(&history.row.doc_id);
+                params.push(&history.row.doc_type);
+                params.push(&history.row.created);
+                params.push(&history.row.value);
+                params.push(&history.row.lang);
+                params.push(row_deleted);
+                params.push(&history.op_id);
+                params.push(&history.date);
+                params.push(&history.update_counter);
+            }
+            let res = if chunk.len() == INSERT_CHUNK_SIZE {
+                self.prepare(query).and_then(|stmt| self.client.execute(&stmt, &params))
+            } else {
+                self.client.execute(query.as_str(), &params)
+            };
+            if let Err(e) = res {
+                error!("failed to insert history, count = {}, history table = {}, err = {}", chunk.len(), table, e);
+                return Err("failed to insert history");
+            }
+        }
+        Ok(())
+    }
+
+    fn create_class_table(&mut self, table: &str) -> Result<(), &'static str> {
+        let query = format!(
+            "CREATE TABLE IF NOT EXISTS {0} ( \
+             \"doc_id\" VARCHAR(128) PRIMARY KEY, \
+             \"created\" TIMESTAMP NULL, \
+             \"deleted\" BOOLEAN NULL); \
+             CREATE INDEX IF NOT EXISTS {1} ON {0} (\"created\");",
+            quote_ident(table),
+            index_ident(table, "c3")
+        );
+        if let Err(e) = self.client.batch_execute(&query) {
+            error!("failed to create class table, err = {}", e);
+            return Err("failed to create class table");
+        }
+        Ok(())
+    }
+
+    fn add_column(&mut self, table: &str, column: &str, column_type: ColumnType) -> Result<(), &'static str> {
+        let query = format!("ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} {} NULL", quote_ident(table), quote_ident(column), sql_type(column_type));
+        if let Err(e) = self.client.batch_execute(&query) {
+            error!("failed to add column {} to table {}, err = {}", column, table, e);
+            return Err("failed to add column");
+        }
+        Ok(())
+    }
+
+    fn widen_column(&mut self, table: &str, column: &str, from: ColumnType, to: ColumnType) -> Result<(), &'static str> {
+        // There is no direct cast of boolean to bigint or double precision, it goes through integer
+        let using = if from == ColumnType::Bool && to != ColumnType::Text {
+            format!("{}::integer::{}", quote_ident(column), sql_type(to))
+        } else {
+            format!("{}::{}", quote_ident(column), sql_type(to))
+        };
+        let mut query = String::new();
+        // Long text values do not fit into the btree index, so the value index of the property table is dropped
+        if to == ColumnType::Text && column == "value" {
+            query.push_str(&format!("DROP INDEX IF EXISTS {}; ", index_ident(table, "civ")));
+        }
+        query.push_str(&format!("ALTER TABLE {} ALTER COLUMN {} TYPE {} USING {}", quote_ident(table), quote_ident(column), sql_type(to), using));
+        if let Err(e) = self.client.batch_execute(&query) {
+            error!("failed to change type of column {} of table {} from {:?} to {:?}, err = {}", column, table, from, to, e);
+            return Err("failed to change type of column");
+        }
+        // Parameter types of the prepared statements of the table are not valid anymore
+        let table_ident = quote_ident(table);
+        self.statements.retain(|query, _| !query.contains(&table_ident));
+        Ok(())
+    }
+
+    fn insert_row(&mut self, table: &str, row: &ClassRow) -> Result<(), &'static str> {
+        let deleted = if row.deleted {
+            Some(true)
+        } else {
+            None
+        };
+        let mut columns = vec!["doc_id".to_owned(), "created".to_owned(), "deleted".to_owned()];
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![&row.doc_id, &row.created, &deleted];
+        for (column, value) in row.values.iter() {
+            columns.push(quote_ident(column));
+            params.push(value);
+        }
+        let placeholders: Vec<String> = (1..=columns.len()).map(|idx| format!("${}", idx)).collect();
+        let query = format!("INSERT INTO {} ({}) VALUES ({})", quote_ident(table), columns.join(", "), placeholders.join(", "));
+        if let Err(e) = self.client.execute(query.as_str(), &params) {
+            error!("failed to insert individual, uri = {}, class table = {}, err = {}", row.doc_id, table, e);
+            return Err("failed to insert individual");
+        }
+        Ok(())
+    }
+
+    fn select_values(&mut self, table: &str, doc_id: &str) -> Result<Vec<(String, Option<String>, String)>, &'static str> {
+        let query = format!("SELECT doc_type::text, value::text, lang::text FROM {} WHERE doc_id = $1", quote_ident(table));
+        match self.prepare(query).and_then(|stmt| self.client.query(&stmt, &[&doc_id])) {
+            Ok(rows) => Ok(rows.iter().map(|row| (row.get(0), row.get(1), row.get::<_, Option<String>>(2).unwrap_or_default())).collect()),
+            Err(e) => {
+                error!("failed to read individual, uri = {}, property table = {}, err = {}", doc_id, table, e);
+                Err("failed to read individual")
+            },
+        }
+    }
+
+    fn select_doc_ids(&mut self, table: &str, after: &str, limit: usize, skip_deleted: bool) -> Result<Vec<String>, &'static str> {
+        let deleted_clause = if skip_deleted {
+            " AND deleted IS NULL"
+        } else {
+            ""
+        };
+        let query = format!("SELECT DISTINCT doc_id::text FROM {} WHERE doc_id > $1{} ORDER BY 1 LIMIT {}", quote_ident(table), deleted_clause, limit);
+        match self.prepare(query).and_then(|stmt| self.client.query(&stmt, &[&after])) {
+            Ok(rows) => Ok(rows.iter().map(|row| row.get(0)).collect()),
+            Err(e) => {
+                error!("failed to read individuals of property table {}, err = {}", table, e);
+                Err("failed to read individuals")
+            },
+        }
+    }
+
+    fn delete(&mut self, table: &str, doc_id: &str) -> Result<(), &'static str> {
+        let query = format!("DELETE FROM {} WHERE doc_id = $1", quote_ident(table));
+        let res = self.prepare(query).and_then(|stmt| self.client.execute(&stmt, &[&doc_id]));
+        if let Err(e) = res {
+            error!("failed to delete individual, uri = {}, property table = {}, err = {}", doc_id, table, e);
+            return Err("failed to delete individual");
+        }
+        Ok(())
+    }
+
+    fn mark_deleted(&mut self, table: &str, doc_id: &str) -> Result<(), &'static str> {
+        let query = format!("UPDATE {} SET deleted = TRUE WHERE doc_id = $1", quote_ident(table));
+        let res = self.prepare(query).and_then(|stmt| self.client.execute(&stmt, &[&doc_id]));
+        if let Err(e) = res {
+            error!("failed to mark individual as deleted, uri = {}, property table = {}, err = {}", doc_id, table, e);
+            return Err("failed to mark individual as deleted");
+        }
+        Ok(())
+    }
+}
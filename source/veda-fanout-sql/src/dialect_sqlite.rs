@@ -0,0 +1,413 @@
+use crate::dialect::{ClassRow, ColumnType, HistoryRow, PredicateRow, SqlDialect, SqlValue, INSERT_CHUNK_SIZE};
+use rusqlite::types::{ToSqlOutput, Value, ValueRef};
+use rusqlite::{params, Connection, ToSql};
+use std::collections::HashMap;
+use v_common::onto::individual::Individual;
+
+// Prepared statements cached by the connection: select, delete and update of every table and the insert of the full chunk of rows
+const STMT_CACHE_SIZE: usize = 4096;
+
+// DROP COLUMN and RENAME COLUMN used to widen a column are supported since SQLite 3.35
+const MIN_SQLITE_VERSION: i32 = 3_035_000;
+
+// Embedded database in a local file, it is used to run the export without a database server
+pub struct SqliteDialect {
+    conn: Connection,
+}
+
+impl SqliteDialect {
+    pub fn connect(connection: &mut Individual) -> Result<Self, &'static str> {
+        let path = match connection.get_first_literal("v-s:sql_database") {
+            Some(path) => path,
+            None => {
+                error!("parameter [sql_database] is required");
+                return Err("parameter [sql_database] is required");
+            },
+        };
+        if rusqlite::version_number() < MIN_SQLITE_VERSION {
+            error!("SQLite {} is not supported, version 3.35 or later is required", rusqlite::version());
+            return Err("unsupported SQLite version");
+        }
+        info!("trying to open sqlite database, path: {}", path);
+        match Connection::open(&path) {
+            Ok(conn) => {
+                conn.set_prepared_statement_cache_capacity(STMT_CACHE_SIZE);
+                info!("SQLite database opened successfully");
+                Ok(SqliteDialect {
+                    conn,
+                })
+            },
+            Err(e) => {
+                error!("failed to open SQLite database, err = {}", e);
+                Err("failed to open SQLite database")
+            },
+        }
+    }
+}
+
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn sql_type(column_type: ColumnType) -> &'static str {
+    match column_type {
+        ColumnType::Bool => "BOOLEAN",
+        ColumnType::Datetime => "DATETIME",
+        ColumnType::Decimal => "REAL",
+        ColumnType::Integer => "INTEGER",
+        ColumnType::Text => "TEXT",
+        ColumnType::Uri => "VARCHAR(128)",
+    }
+}
+
+fn parse_column_type(data_type: &str) -> Option<ColumnType> {
+    match data_type {
+        "BOOLEAN" => Some(ColumnType::Bool),
+        "INTEGER" => Some(ColumnType::Integer),
+        "REAL" => Some(ColumnType::Decimal),
+        "DATETIME" => Some(ColumnType::Datetime),
+        "VARCHAR(128)" => Some(ColumnType::Uri),
+        "TEXT" => Some(ColumnType::Text),
+        _ => None,
+    }
+}
+
+// SQLite has no boolean and datetime storage classes, booleans are stored as 0/1 and datetimes as text
+impl ToSql for SqlValue {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            SqlValue::Null => ToSqlOutput::Owned(Value::Null),
+            SqlValue::Bool(bool_value) => ToSqlOutput::Owned(Value::Integer(*bool_value as i64)),
+            SqlValue::Int(int_value) => ToSqlOutput::Owned(Value::Integer(*int_value)),
+            SqlValue::Decimal(num_value) => ToSqlOutput::Owned(Value::Real(*num_value)),
+            SqlValue::Str(str_value) => ToSqlOutput::Borrowed(ValueRef::Text(str_value.as_bytes())),
+            SqlValue::Datetime(_) => ToSqlOutput::Owned(Value::Text(self.to_text())),
+        })
+    }
+}
+
+fn deleted_value(deleted: bool) -> Option<bool> {
+    if deleted {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+fn datetime_value(datetime: &Option<chrono::NaiveDateTime>) -> SqlValue {
+    datetime.map_or(SqlValue::Null, SqlValue::Datetime)
+}
+
+impl SqlDialect for SqliteDialect {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn read_columns(&mut self) -> Result<HashMap<String, HashMap<String, ColumnType>>, &'static str> {
+        let mut tables: HashMap<String, HashMap<String, ColumnType>> = HashMap::new();
+        let res =
+            self.conn.prepare("SELECT m.name, p.name, p.type FROM sqlite_master AS m JOIN pragma_table_info(m.name) AS p WHERE m.type = 'table'").and_then(|mut stmt| {
+                let rows = stmt.query_map(params![], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?)))?;
+                rows.collect::<rusqlite::Result<Vec<(String, String, String)>>>()
+            });
+        match res {
+            Ok(rows) => {
+                for (table, column, data_type) in rows {
+                    let columns = tables.entry(table).or_default();
+                    if let Some(column_type) = parse_column_type(&data_type.to_uppercase()) {
+                        columns.insert(column, column_type);
+                    }
+                }
+                debug!("Existing tables: {:?}", tables);
+                Ok(tables)
+            },
+            Err(e) => {
+                error!("failed to read table columns, err = {}", e);
+                Err("failed to read table columns")
+            },
+        }
+    }
+
+    fn begin(&mut self) -> Result<(), &'static str> {
+        if let Err(e) = self.conn.execute_batch("BEGIN") {
+            error!("failed to start transaction, err = {}", e);
+            return Err("failed to start transaction");
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), &'static str> {
+        if let Err(e) = self.conn.execute_batch("COMMIT") {
+            error!("failed to commit transaction, err = {}", e);
+            return Err("failed to commit transaction");
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), &'static str> {
+        if let Err(e) = self.conn.execute_batch("ROLLBACK") {
+            error!("failed to roll back transaction, err = {}", e);
+            return Err("failed to roll back transaction");
+        }
+        Ok(())
+    }
+
+    fn create_predicate_table(&mut self, table: &str, column_type: ColumnType) -> Result<(), &'static str> {
+        // Index names are local to the database in SQLite, so they are prefixed by the table name
+        let mut query = format!(
+            "CREATE TABLE IF NOT EXISTS {0} ( \
+             \"ID\" INTEGER PRIMARY KEY AUTOINCREMENT, \
+             \"doc_id\" VARCHAR(128) NOT NULL, \
+             \"doc_type\" VARCHAR(128) NOT NULL, \
+             \"created\" DATETIME NULL, \
+             \"value\" {1} NULL, \
+             \"lang\" CHAR(2) NULL, \
+             \"deleted\" BOOLEAN NULL); \
+             CREATE INDEX IF NOT EXISTS {2} ON {0} (\"doc_id\"); \
+             CREATE INDEX IF NOT EXISTS {3} ON {0} (\"doc_type\"); \
+             CREATE INDEX IF NOT EXISTS {4} ON {0} (\"created\"); \
+             CREATE INDEX IF NOT EXISTS {5} ON {0} (\"lang\");",
+            quote_ident(table),
+            sql_type(column_type),
+            quote_ident(&format!("{}_c1", table)),
+            quote_ident(&format!("{}_c2", table)),
+            quote_ident(&format!("{}_c3", table)),
+            quote_ident(&format!("{}_c4", table))
+        );
+        if column_type != ColumnType::Text {
+            query.push_str(&format!(" CREATE INDEX IF NOT EXISTS {} ON {} (\"value\");", quote_ident(&format!("{}_civ", table)), quote_ident(table)));
+        }
+
+        if let Err(e) = self.conn.execute_batch(&query) {
+            error!("failed to create property table, err = {}", e);
+            return Err("failed to create property table");
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, table: &str, rows: &[&PredicateRow]) -> Result<(), &'static str> {
+        for chunk in rows.chunks(INSERT_CHUNK_SIZE) {
+            let query = format!(
+                "INSERT INTO {} (doc_id, doc_type, created, value, lang, deleted) VALUES {}",
+                quote_ident(table),
+                vec!["(?, ?, ?, ?, ?, ?)"; chunk.len()].join(", ")
+            );
+            let created: Vec<SqlValue> = chunk.iter().map(|row| datetime_value(&row.created)).collect();
+            let deleted: Vec<Option<bool>> = chunk.iter().map(|row| deleted_value(row.deleted)).collect();
+            let mut params: Vec<&dyn ToSql> = Vec::with_capacity(chunk.len() * 6);
+            for (idx, row) in chunk.iter().enumerate() {
+                params.push(&row.doc_id);
+                params.push(&row.doc_type);
+                params.push(&created[idx]);
+                params.push(&row.value);
+                params.push(&row.lang);
+                params.push(&deleted[idx]);
+            }
+            // Only statements of the full chunk size are kept prepared
+            let res = if chunk.len() == INSERT_CHUNK_SIZE {
+                self.conn.prepare_cached(&query).and_then(|mut stmt| stmt.execute(&params[..]))
+            } else {
+                self.conn.execute(&query, &params[..])
+            };
+            if let Err(e) = res {
+                error!("failed to insert individuals, count = {}, property table = {}, err = {}", chunk.len(), table, e);
+                return Err("failed to insert individuals");
+            }
+        }
+        Ok(())
+    }
+
+    fn create_history_table(&mut self, table: &str, column_type: ColumnType) -> Result<(), &'static str> {
+        let mut query = format!(
+            "CREATE TABLE IF NOT EXISTS {0} ( \
+             \"ID\" INTEGER PRIMARY KEY AUTOINCREMENT, \
+             \"doc_id\" VARCHAR(128) NOT NULL, \
+             \"doc_type\" VARCHAR(128) NOT NULL, \
+             \"created\" DATETIME NULL, \
+             \"value\" {1} NULL, \
+             \"lang\" CHAR(2) NULL, \
+             \"deleted\" BOOLEAN NULL, \
+             \"op_id\" INTEGER NULL, \
+             \"date\" DATETIME NULL, \
+             \"update_counter\" INTEGER NULL); \
+             CREATE INDEX IF NOT EXISTS {2} ON {0} (\"doc_id\", \"date\"); \
+             CREATE INDEX IF NOT EXISTS {3} ON {0} (\"doc_type\"); \
+             CREATE INDEX IF NOT EXISTS {4} ON {0} (\"op_id\");",
+            quote_ident(table),
+            sql_type(column_type),
+            quote_ident(&format!("{}_c1", table)),
+            quote_ident(&format!("{}_c2", table)),
+            quote_ident(&format!("{}_c5", table))
+        );
+        if column_type != ColumnType::Text {
+            query.push_str(&format!(" CREATE INDEX IF NOT EXISTS {} ON {} (\"value\");", quote_ident(&format!("{}_civ", table)), quote_ident(table)));
+        }
+
+        if let Err(e) = self.conn.execute_batch(&query) {
+            error!("failed to create history table, err = {}", e);
+            return Err("failed to create history table");
+        }
+        Ok(())
+    }
+
+    fn insert_history(&mut self, table: &str, rows: &[&HistoryRow]) -> Result<(), &'static str> {
+        for chunk in rows.chunks(INSERT_CHUNK_SIZE) {
+            let query = format!(
+                "INSERT INTO {} (doc_id, doc_type, created, value, lang, deleted, op_id, date, update_counter) VALUES {}",
+                quote_ident(table),
+                vec!["(?, ?, ?, ?, ?, ?, ?, ?, ?)"; chunk.len()].join(", ")
+            );
+            let created: Vec<SqlValue> = chunk.iter().map(|history| datetime_value(&history.row.created)).collect();
+            let deleted: Vec<Option<bool>> = chunk.iter().map(|history| deleted_value(history.row.deleted)).collect();
+            let date: Vec<SqlValue> = chunk.iter().map(|history| datetime_value(&history.date)).collect();
+            let mut params: Vec<&dyn ToSql> = Vec::with_capacity(chunk.len() * 9);
+            for (idx, history) in chunk.iter().enumerate() {
+                params.push(&history.row.doc_id);
+                params.push(&history.row.doc_type);
+                params.push(&created[idx]);
+                params.push(&history.row.value);
+                params.push(&history.row.lang);
+                params.push(&deleted[idx]);
+                params.push(&history.op_id);
+                params.push(&date[idx]);
+                params.push(&history.update_counter);
+            }
+            let res = if chunk.len() == INSERT_CHUNK_SIZE {
+                self.conn.prepare_cached(&query).and_then(|mut stmt| stmt.execute(&params[..]))
+            } else {
+                self.conn.execute(&query, &params[..])
+            };
+            if let Err(e) = res {
+                error!("failed to insert history, count = {}, history table = {}, err = {}", chunk.len(), table, e);
+                return Err("failed to insert history");
+            }
+        }
+        Ok(())
+    }
+
+    fn create_class_table(&mut self, table: &str) -> Result<(), &'static str> {
+        let query = format!(
+            "CREATE TABLE IF NOT EXISTS {0} ( \
+             \"doc_id\" VARCHAR(128) PRIMARY KEY, \
+             \"created\" DATETIME NULL, \
+             \"deleted\" BOOLEAN NULL); \
+             CREATE INDEX IF NOT EXISTS {1} ON {0} (\"created\");",
+            quote_ident(table),
+            quote_ident(&format!("{}_c3", table))
+        );
+        if let Err(e) = self.conn.execute_batch(&query) {
+            error!("failed to create class table, err = {}", e);
+            return Err("failed to create class table");
+        }
+        Ok(())
+    }
+
+    fn add_column(&mut self, table: &str, column: &str, column_type: ColumnType) -> Result<(), &'static str> {
+        let query = format!("ALTER TABLE {} ADD COLUMN {} {} NULL", quote_ident(table), quote_ident(column), sql_type(column_type));
+        if let Err(e) = self.conn.execute_batch(&query) {
+            error!("failed to add column {} to table {}, err = {}", column, table, e);
+            return Err("failed to add column");
+        }
+        Ok(())
+    }
+
+    fn widen_column(&mut self, table: &str, column: &str, from: ColumnType, to: ColumnType) -> Result<(), &'static str> {
+        // SQLite can not change the type of column, the values are copied to a new column which replaces the old one (SQLite 3.35 or later).
+        // An indexed column can not be dropped, so the value index of the property table is dropped and created again
+        let tmp_column = format!("{}__widen", column);
+        let mut query = String::new();
+        if column == "value" {
+            query.push_str(&format!("DROP INDEX IF EXISTS {}; ", quote_ident(&format!("{}_civ", table))));
+        }
+        query.push_str(&format!(
+            "ALTER TABLE {0} ADD COLUMN {1} {2} NULL; \
+             UPDATE {0} SET {1} = CAST({3} AS {2}); \
+             ALTER TABLE {0} DROP COLUMN {3}; \
+             ALTER TABLE {0} RENAME COLUMN {1} TO {3};",
+            quote_ident(table),
+            quote_ident(&tmp_column),
+            sql_type(to),
+            quote_ident(column)
+        ));
+        if column == "value" && to != ColumnType::Text {
+            query.push_str(&format!(" CREATE INDEX IF NOT EXISTS {} ON {} (\"value\");", quote_ident(&format!("{}_civ", table)), quote_ident(table)));
+        }
+        if let Err(e) = self.conn.execute_batch(&query) {
+            error!("failed to change type of column {} of table {} from {:?} to {:?}, err = {}", column, table, from, to, e);
+            return Err("failed to change type of column");
+        }
+        // Prepared statements of the table refer to the dropped column
+        self.conn.flush_prepared_statement_cache();
+        Ok(())
+    }
+
+    fn insert_row(&mut self, table: &str, row: &ClassRow) -> Result<(), &'static str> {
+        let created = datetime_value(&row.created);
+        let deleted = deleted_value(row.deleted);
+        let mut columns = vec!["doc_id".to_owned(), "created".to_owned(), "deleted".to_owned()];
+        let mut params: Vec<&dyn ToSql> = vec![&row.doc_id, &created, &deleted];
+        for (column, value) in row.values.iter() {
+            columns.push(quote_ident(column));
+            params.push(value);
+        }
+        let query = format!("INSERT INTO {} ({}) VALUES ({})", quote_ident(table), columns.join(", "), vec!["?"; columns.len()].join(", "));
+        if let Err(e) = self.conn.execute(&query, &params[..]) {
+            error!("failed to insert individual, uri = {}, class table = {}, err = {}", row.doc_id, table, e);
+            return Err("failed to insert individual");
+        }
+        Ok(())
+    }
+
+    fn select_values(&mut self, table: &str, doc_id: &str) -> Result<Vec<(String, Option<String>, String)>, &'static str> {
+        let query = format!("SELECT doc_type, CAST(value AS TEXT), lang FROM {} WHERE doc_id = ?", quote_ident(table));
+        let res = self.conn.prepare_cached(&query).and_then(|mut stmt| {
+            let rows = stmt.query_map(params![doc_id], |row| Ok((row.get(0)?, row.get(1)?, row.get::<_, Option<String>>(2)?.unwrap_or_default())))?;
+            rows.collect::<rusqlite::Result<Vec<(String, Option<String>, String)>>>()
+        });
+        match res {
+            Ok(rows) => Ok(rows),
+            Err(e) => {
+                error!("failed to read individual, uri = {}, property table = {}, err = {}", doc_id, table, e);
+                Err("failed to read individual")
+            },
+        }
+    }
+
+    fn select_doc_ids(&mut self, table: &str, after: &str, limit: usize, skip_deleted: bool) -> Result<Vec<String>, &'static str> {
+        let deleted_clause = if skip_deleted {
+            " AND deleted IS NULL"
+        } else {
+            ""
+        };
+        let query = format!("SELECT DISTINCT doc_id FROM {} WHERE doc_id > ?{} ORDER BY doc_id LIMIT {}", quote_ident(table), deleted_clause, limit);
+        let res = self.conn.prepare_cached(&query).and_then(|mut stmt| {
+            let rows = stmt.query_map(params![after], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<Vec<String>>>()
+        });
+        match res {
+            Ok(doc_ids) => Ok(doc_ids),
+            Err(e) => {
+                error!("failed to read individuals of property table {}, err = {}", table, e);
+                Err("failed to read individuals")
+            },
+        }
+    }
+
+    fn delete(&mut self, table: &str, doc_id: &str) -> Result<(), &'static str> {
+        let query = format!("DELETE FROM {} WHERE doc_id = ?", quote_ident(table));
+        if let Err(e) = self.conn.prepare_cached(&query).and_then(|mut stmt| stmt.execute(params![doc_id])) {
+            error!("failed to delete individual, uri = {}, property table = {}, err = {}", doc_id, table, e);
+            return Err("failed to delete individual");
+        }
+        Ok(())
+    }
+
+    fn mark_deleted(&mut self, table: &str, doc_id: &str) -> Result<(), &'static str> {
+        let query = format!("UPDATE {} SET deleted = 1 WHERE doc_id = ?", quote_ident(table));
+        if let Err(e) = self.conn.prepare_cached(&query).and_then(|mut stmt| stmt.execute(params![doc_id])) {
+            error!("failed to mark individual as deleted, uri = {}, property table = {}, err = {}", doc_id, table, e);
+            return Err("failed to mark individual as deleted");
+        }
+        Ok(())
+    }
+}
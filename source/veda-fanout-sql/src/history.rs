@@ -0,0 +1,119 @@
+use crate::batch::BatchOp;
+use crate::dialect::{resource_lang, resource_to_value, truncate_name, HistoryRow, PredicateRow, SqlValue};
+use crate::{check_create_predicate_table, compare_resources, push_ops, Context};
+use chrono::NaiveDateTime;
+use v_common::onto::individual::Individual;
+
+// The suffix is kept within the 64 characters of the table name
+pub fn history_table_name(predicate: &str) -> String {
+    format!("{}_history", truncate_name(&predicate.to_lowercase(), 56))
+}
+
+// Appends the values of the changed predicates to the history tables, a removed predicate is recorded by a row with Null value.
+// The removal of individual is recorded by the rows with Null value of its every predicate, marked as deleted.
+// Returns Err if the export is aborted, the caller sends the element to the dead letter queue
+pub fn export(
+    new_state: &mut Individual,
+    prev_state: &mut Individual,
+    classes: &[String],
+    is_remove: bool,
+    op_id: Option<i64>,
+    date: Option<NaiveDateTime>,
+    ctx: &mut Context,
+) -> Result<(), &'static str> {
+    if classes.contains(&"v-s:Version".to_owned()) {
+        return Ok(());
+    }
+
+    let (uri, update_counter, created) = {
+        let state = if is_remove {
+            &mut *prev_state
+        } else {
+            &mut *new_state
+        };
+        (
+            state.get_id().to_string(),
+            state.get_first_integer("v-s:updateCounter"),
+            state.get_first_datetime("v-s:created").map(|timestamp| NaiveDateTime::from_timestamp(timestamp, 0)),
+        )
+    };
+    let is_deleted = is_remove || classes.contains(&"v-s:Deletable".to_owned()) || new_state.is_exists_bool("v-s:deleted", true);
+    let types: Vec<&String> = classes.iter().filter(|class| *class != "v-s:Deletable").collect();
+
+    let mut predicates = new_state.get_predicates();
+    for predicate in prev_state.get_predicates() {
+        if !predicates.contains(&predicate) {
+            predicates.push(predicate);
+        }
+    }
+
+    let mut ops = vec![];
+    let mut tr_error = false;
+    for predicate in predicates.iter() {
+        let nsr = new_state.get_resources(predicate);
+        let psr = prev_state.get_resources(predicate);
+        if !is_remove && compare_resources(&nsr, &psr) {
+            continue;
+        }
+
+        let table = ctx.db.ident_name(&history_table_name(predicate));
+        let mut values = vec![];
+        match nsr.as_ref().filter(|resources| !resources.is_empty()) {
+            Some(resources) => {
+                for resource in resources.iter() {
+                    if check_create_predicate_table(&mut ctx.history_tables, &table, resource, true, ctx.db.as_mut()).is_err() {
+                        error!("failed to create history table, export aborted, property = {}, uri = {}", predicate, uri);
+                        tr_error = true;
+                    }
+                    let value = resource_to_value(resource);
+                    if let SqlValue::Str(str_value) = &value {
+                        if str_value == "v-s:Deletable" {
+                            continue;
+                        }
+                    }
+                    values.push((value, resource_lang(resource)));
+                }
+            },
+            None => {
+                // The table is created by the type of the removed value
+                if let Some(resource) = psr.as_ref().and_then(|resources| resources.first()) {
+                    if check_create_predicate_table(&mut ctx.history_tables, &table, resource, true, ctx.db.as_mut()).is_err() {
+                        error!("failed to create history table, export aborted, property = {}, uri = {}", predicate, uri);
+                        tr_error = true;
+                    }
+                }
+                values.push((SqlValue::Null, String::from("NO")));
+            },
+        }
+
+        for class in types.iter() {
+            for (value, lang) in values.iter() {
+                let row = HistoryRow {
+                    row: PredicateRow {
+                        doc_id: uri.clone(),
+                        doc_type: class.to_string(),
+                        created,
+                        value: value.clone(),
+                        lang: lang.to_owned(),
+                        deleted: is_deleted,
+                    },
+                    op_id,
+                    date,
+                    update_counter,
+                };
+                ops.push(BatchOp::InsertHistory(table.clone(), row));
+            }
+        }
+    }
+
+    if tr_error {
+        error!("history export aborted, uri = {}", uri);
+        return Err("failed to create history table");
+    }
+
+    if !ops.is_empty() {
+        debug!("prepared history uri = {}, op_id = {:?}, statements = {}", uri, op_id, ops.len());
+        push_ops(ctx, uri, ops);
+    }
+    Ok(())
+}
@@ -2,29 +2,77 @@
 extern crate log;
 extern crate mysql;
 
+mod backfill;
+mod batch;
+mod class_table;
+mod dead_letter;
+mod dialect;
+mod dialect_mysql;
+mod dialect_postgresql;
+mod dialect_sqlite;
+mod history;
+mod reconcile;
+
+use crate::backfill::backfill;
+use crate::batch::{write_batch, BatchOp, BatchResult, IndividualOps};
+use crate::dead_letter::{redrive, serialize_element, DeadLetterQueue, Source};
+use crate::dialect::{connect_to_sql, resource_lang, resource_to_value, ColumnType, PredicateRow, SqlDialect, SqlValue};
+use crate::reconcile::reconcile;
 use chrono::prelude::*;
-use std::collections::HashMap;
-use std::{process, thread, time};
+use std::collections::{HashMap, HashSet};
+use std::{env, process};
 
 use v_common::module::common::load_onto;
 use v_common::module::info::ModuleInfo;
 use v_common::module::module_impl::{get_cmd, get_info_of_module, get_inner_binobj_as_individual, init_log, wait_load_ontology, wait_module, Module, PrepareError};
 use v_common::module::veda_backend::Backend;
-use v_common::onto::datatype::DataType;
 use v_common::onto::individual::Individual;
 use v_common::onto::onto_impl::Onto;
-use v_common::onto::resource::{Resource, Value};
+use v_common::onto::resource::Resource;
 use v_common::v_api::api_client::IndvOp;
 use v_common::v_api::obj::ResultCode;
 use v_common::v_queue::consumer::Consumer;
 
+const BATCH_SIZE: u32 = 10_000;
+
+#[derive(Debug, PartialEq)]
+enum TableMode {
+    // One table per predicate: doc_id, doc_type, created, value, lang, deleted
+    Predicate,
+    // One table per exportable class with a column per predicate
+    Class,
+}
+
+#[derive(Debug)]
+struct Shard {
+    index: u64,
+    count: u64,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum RemoveMode {
+    Delete,
+    MarkDeleted,
+}
+
 pub struct Context {
     onto: Onto,
     classes_indvs: HashMap<String, Individual>,
-    pool: mysql::Pool,
-    tables: HashMap<String, bool>,
+    db: Box<dyn SqlDialect>,
+    tables: HashMap<String, ColumnType>,
+    history_tables: HashMap<String, ColumnType>,
+    columns: HashMap<String, HashMap<String, ColumnType>>,
     module_info: ModuleInfo,
     fanout_id: Option<i64>,
+    table_mode: TableMode,
+    remove_mode: RemoveMode,
+    shard: Option<Shard>,
+    history: bool,
+    batch: Vec<IndividualOps>,
+    last_op_id: Option<i64>,
+    dead_letter: DeadLetterQueue,
+    // Serialized element of backfill, redrive and reconciliation, the changes from the queue keep the states of element instead
+    source: Vec<u8>,
 }
 
 fn main() {
@@ -40,11 +88,51 @@ fn main() {
         None
     };
 
-    let consumer_name = if let Some(priority) = fanout_id {
+    let table_mode = match Module::get_property::<String>("table_mode").as_deref() {
+        Some("class") => TableMode::Class,
+        _ => TableMode::Predicate,
+    };
+
+    let remove_mode = match Module::get_property::<String>("remove_mode").as_deref() {
+        Some("mark") => RemoveMode::MarkDeleted,
+        _ => RemoveMode::Delete,
+    };
+
+    // Every change of the exported individuals is also appended to the <predicate>_history tables
+    let history = Module::get_property::<String>("export_history").as_deref() == Some("true");
+
+    // Worker <shard> of <shards> exports the individuals with hash(uri) % shards == shard
+    let shard = if let Some(count) = Module::get_property::<String>("shards") {
+        let count = count.parse::<u64>().expect(&format!("invalid value {} in parameter [shards]", count));
+        let index = Module::get_property::<String>("shard").expect("parameter [shard] is required with [shards]");
+        let index = index.parse::<u64>().expect(&format!("invalid value {} in parameter [shard]", index));
+        if count == 0 || index >= count {
+            error!("invalid shard {} of {}", index, count);
+            process::exit(101);
+        }
+        Some(Shard {
+            index,
+            count,
+        })
+    } else {
+        None
+    };
+
+    // Statements are written to the file instead of the database
+    let dry_run = env::args().find_map(|arg| arg.strip_prefix("--dry-run=").map(|path| path.to_owned()));
+
+    let mut consumer_name = if let Some(priority) = fanout_id {
         format!("fanout_sql_{}", priority)
     } else {
         "fanout_sql".to_string()
     };
+    if let Some(shard) = &shard {
+        consumer_name = format!("{}_shard_{}_of_{}", consumer_name, shard.index, shard.count);
+    }
+    // The dry run reads the queue by its own consumer, so the position of export is not changed
+    if dry_run.is_some() {
+        consumer_name = format!("{}_dry_run", consumer_name);
+    }
 
     let mut queue_consumer = Consumer::new("./data/queue", &consumer_name, "individuals-flow").expect("!!!!!!!!! FAIL QUEUE");
     let module_info = ModuleInfo::new("./data", &consumer_name, true);
@@ -55,31 +143,99 @@ fn main() {
     let mut module = Module::default();
     let mut backend = Backend::default();
 
-    let pool = match connect_to_mysql(&mut backend, 5, 20000) {
+    let mut db = match connect_to_sql(&mut backend, 5, 20000) {
         Err(_) => process::exit(101),
-        Ok(pool) => pool,
+        Ok(db) => db,
     };
 
-    let tables = match read_tables(&pool) {
+    if let Some(path) = &dry_run {
+        if db.set_dry_run(path).is_err() {
+            process::exit(101);
+        }
+    }
+
+    let columns = match db.read_columns() {
         Err(_) => process::exit(101),
-        Ok(tables) => tables,
+        Ok(columns) => columns,
+    };
+
+    // Converts existing tables to the engine and charset of the connection and exits, the tables not made by export are not checked
+    let own_tables: HashSet<String> = columns.iter().filter(|(_, table_columns)| is_own_table(table_columns)).map(|(table, _)| table.to_owned()).collect();
+    if env::args().any(|arg| arg == "--migrate_engine") {
+        match db.check_tables(&own_tables, true) {
+            Ok(_) => process::exit(0),
+            Err(_) => process::exit(101),
+        }
+    }
+    if db.check_tables(&own_tables, false).is_err() {
+        process::exit(101);
+    }
+
+    // Predicate tables are the tables with the value column, the type of the value column is tracked to detect conflicts.
+    // History tables have the value column too, they are told apart by the op_id column
+    let mut tables: HashMap<String, ColumnType> = HashMap::new();
+    let mut history_tables: HashMap<String, ColumnType> = HashMap::new();
+    for (table, table_columns) in columns.iter() {
+        if let Some(column_type) = table_columns.get("value") {
+            if table_columns.contains_key("op_id") {
+                history_tables.insert(table.to_owned(), *column_type);
+            } else {
+                tables.insert(table.to_owned(), *column_type);
+            }
+        }
+    }
+
+    let dead_letter = match DeadLetterQueue::new(&consumer_name) {
+        Err(_) => process::exit(101),
+        Ok(dead_letter) => dead_letter,
     };
 
     if let Some(id) = fanout_id {
         warn!("A FILTER TYPE WITH v-s:exportPrioritySQL = {}", id);
     }
+    info!("table mode: {:?}, remove mode: {:?}, shard: {:?}, history: {}", table_mode, remove_mode, shard, history);
 
     let mut ctx = Context {
         onto: Onto::default(),
         classes_indvs: Default::default(),
-        pool,
+        db,
         tables,
+        history_tables,
+        columns,
         module_info: module_info.unwrap(),
         fanout_id,
+        table_mode,
+        remove_mode,
+        shard,
+        history,
+        batch: vec![],
+        last_op_id: None,
+        dead_letter,
+        source: vec![],
     };
 
     load_onto(&mut backend.storage, &mut ctx.onto);
 
+    // Exports again the failed elements after the cause is fixed and exits
+    if env::args().any(|arg| arg == "--redrive_dead_letter") {
+        redrive(&mut backend, &mut ctx, &consumer_name);
+        process::exit(0);
+    }
+
+    // Compares the tables with storage, writes the report and exits. If repair is set, the individuals with differences are exported again
+    // and the rows of removed individuals and predicates are removed
+    if env::args().any(|arg| arg == "--reconcile") {
+        let report_path = Module::get_property::<String>("reconcile_report").unwrap_or_else(|| format!("./{}-reconcile.csv", consumer_name));
+        let repair = env::args().any(|arg| arg == "--reconcile_repair");
+        reconcile(&mut backend, &mut ctx, &report_path, repair);
+        process::exit(0);
+    }
+
+    // Existing individuals are exported before the changes from the queue
+    if env::args().any(|arg| arg == "--backfill") {
+        backfill(&mut backend, &mut ctx, &consumer_name);
+    }
+
     module.listen_queue(
         &mut queue_consumer,
         &mut ctx,
@@ -91,14 +247,91 @@ fn main() {
     );
 }
 
+// Tables made by export: predicate, child and history tables, class tables, all of them have the doc_id, created and deleted columns
+fn is_own_table(columns: &HashMap<String, ColumnType>) -> bool {
+    columns.contains_key("doc_id") && columns.contains_key("created") && columns.contains_key("deleted")
+}
+
 fn heartbeat(_module: &mut Backend, _ctx: &mut Context) -> Result<(), PrepareError> {
     Ok(())
 }
 fn before_bath(_module: &mut Backend, _ctx: &mut Context, _size_batch: u32) -> Option<u32> {
-    None
+    Some(BATCH_SIZE)
 }
-fn void(_module: &mut Backend, _ctx: &mut Context, _prepared_batch_size: u32) -> Result<bool, PrepareError> {
-    Ok(false)
+fn void(_module: &mut Backend, ctx: &mut Context, _prepared_batch_size: u32) -> Result<bool, PrepareError> {
+    if flush_batch(ctx).is_err() {
+        error!("failed to export batch, queue position is not committed");
+        return Err(PrepareError::Fatal);
+    }
+
+    if let Some(op_id) = ctx.last_op_id.take() {
+        if let Err(e) = ctx.module_info.put_info(op_id, op_id) {
+            error!("failed to write module_info, op_id = {}, err = {:?}", op_id, e);
+        }
+    }
+    Ok(true)
+}
+
+// Writes the collected batch in one transaction, if it fails the individuals are written one by one,
+// so a bad individual is skipped the same way as without batching
+fn flush_batch(ctx: &mut Context) -> Result<(), &'static str> {
+    if ctx.batch.is_empty() {
+        return Ok(());
+    }
+    let mut batch = std::mem::take(&mut ctx.batch);
+
+    if let BatchResult::Committed = write_batch(ctx.db.as_mut(), &batch)? {
+        for indv in batch.iter() {
+            info!("Ok, uri = {}", indv.uri);
+        }
+        return Ok(());
+    }
+
+    warn!("failed to export batch of {} individuals, retry one by one", batch.len());
+    for indv in batch.iter_mut() {
+        match write_batch(ctx.db.as_mut(), std::slice::from_ref(indv))? {
+            BatchResult::Committed => info!("Ok, uri = {}", indv.uri),
+            BatchResult::RolledBack(e) => {
+                error!("transaction rolled back, export aborted, uri = {}", indv.uri);
+                ctx.dead_letter.push(&indv.uri, &indv.source.serialize(), e);
+            },
+        }
+    }
+    Ok(())
+}
+
+fn push_ops(ctx: &mut Context, uri: String, ops: Vec<BatchOp>) {
+    ctx.batch.push(IndividualOps {
+        uri,
+        ops,
+        source: Source::Serialized(ctx.source.clone()),
+    });
+}
+
+// Joins the changes of individual collected since start, so they are written or rolled back together
+fn merge_ops(ctx: &mut Context, start: usize, uri: &str) {
+    if ctx.batch.len() > start + 1 {
+        let ops = ctx.batch.drain(start..).flat_map(|indv_ops| indv_ops.ops).collect();
+        push_ops(ctx, uri.to_string(), ops);
+    }
+}
+
+// Individuals are distributed between the shards by the hash of uri, so the changes of one individual are exported by one worker in the queue order
+fn is_own_shard(ctx: &Context, uri: &str) -> bool {
+    match &ctx.shard {
+        Some(shard) => fnv1a_hash(uri) % shard.count == shard.index,
+        None => true,
+    }
+}
+
+// The hash must be the same in every worker process and build, so FNV-1a is used instead of DefaultHasher
+fn fnv1a_hash(value: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in value.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
 }
 
 fn get_priority(backend: &mut Backend, ctx: &mut Context, class_name: &str) -> Option<i64> {
@@ -118,6 +351,21 @@ fn get_priority(backend: &mut Backend, ctx: &mut Context, class_name: &str) -> O
     None
 }
 
+fn is_exportable_class(backend: &mut Backend, ctx: &mut Context, class: &str) -> bool {
+    if ctx.onto.is_some_entered(class, &["v-s:Exportable"]) {
+        let priority = get_priority(backend, ctx, class);
+        if let Some(fid) = ctx.fanout_id {
+            return priority.unwrap_or(99999) == fid;
+        }
+        return priority.is_none();
+    }
+    false
+}
+
+fn is_exportable(backend: &mut Backend, ctx: &mut Context, classes: &[String]) -> bool {
+    classes.iter().any(|class| is_exportable_class(backend, ctx, class))
+}
+
 fn process(_module: &mut Backend, ctx: &mut Context, queue_element: &mut Individual, _my_consumer: &Consumer) -> Result<bool, PrepareError> {
     let cmd = get_cmd(queue_element);
     if cmd.is_none() {
@@ -126,45 +374,127 @@ fn process(_module: &mut Backend, ctx: &mut Context, queue_element: &mut Individ
     }
     let cmd = cmd.unwrap();
 
-    if cmd == IndvOp::Remove {
-        return Ok(true);
+    ctx.last_op_id = queue_element.get_first_integer("op_id");
+
+    let mut prev_state = Individual::default();
+    let is_new = !get_inner_binobj_as_individual(queue_element, "prev_state", &mut prev_state);
+
+    let mut new_state = Individual::default();
+    if cmd != IndvOp::Remove {
+        get_inner_binobj_as_individual(queue_element, "new_state", &mut new_state);
     }
 
-    let op_id = queue_element.get_first_integer("op_id").unwrap_or_default();
-    if let Err(e) = ctx.module_info.put_info(op_id, op_id) {
-        error!("failed to write module_info, op_id = {}, err = {:?}", op_id, e);
+    let uri = if cmd == IndvOp::Remove {
+        prev_state.get_id().to_string()
+    } else {
+        new_state.get_id().to_string()
+    };
+    if !is_own_shard(ctx, &uri) {
+        return Ok(false);
     }
 
-    let mut prev_state = Individual::default();
+    // The element is serialized only if it goes to the dead letter queue
+    ctx.source.clear();
+    let start = ctx.batch.len();
+    let date = queue_element.get_first_datetime("date");
+    let mut res = Ok(());
 
-    let mut is_new = false;
-    if cmd != IndvOp::Remove {
-        is_new = !get_inner_binobj_as_individual(queue_element, "prev_state", &mut prev_state);
+    if cmd == IndvOp::Remove {
+        if let Some(classes) = prev_state.get_literals("rdf:type") {
+            if ctx.table_mode == TableMode::Class {
+                let exportable: Vec<String> = classes.into_iter().filter(|class| is_exportable_class(_module, ctx, class)).collect();
+                class_table::remove(&mut prev_state, &exportable, ctx.remove_mode, ctx);
+            } else if is_exportable(_module, ctx, &classes) {
+                remove(&mut prev_state, &classes, ctx.remove_mode, ctx);
+            }
+        }
+    } else if let Some(classes) = new_state.get_literals("rdf:type") {
+        if ctx.table_mode == TableMode::Class {
+            // Rows of the classes the individual no longer belongs to are removed
+            if let Some(prev_classes) = prev_state.get_literals("rdf:type") {
+                let excluded: Vec<String> = prev_classes.into_iter().filter(|class| !classes.contains(class) && is_exportable_class(_module, ctx, class)).collect();
+                class_table::remove(&mut prev_state, &excluded, ctx.remove_mode, ctx);
+            }
+            let exportable: Vec<String> = classes.into_iter().filter(|class| is_exportable_class(_module, ctx, class)).collect();
+            res = class_table::export(&mut new_state, &mut prev_state, &exportable, is_new, ctx);
+        } else if is_exportable(_module, ctx, &classes) {
+            res = export(&mut new_state, &mut prev_state, &classes, is_new, ctx);
+        }
     }
 
-    let mut new_state = Individual::default();
-    get_inner_binobj_as_individual(queue_element, "new_state", &mut new_state);
-
-    if let Some(classes) = new_state.get_literals("rdf:type") {
-        for class in &classes {
-            if ctx.onto.is_some_entered(class, &["v-s:Exportable"]) {
-                let priority = get_priority(_module, ctx, class);
-                if let Some(fid) = ctx.fanout_id {
-                    if priority.unwrap_or(99999) == fid {
-                        return export(&mut new_state, &mut prev_state, &classes, is_new, ctx);
-                    }
-                } else {
-                    if priority.is_none() {
-                        return export(&mut new_state, &mut prev_state, &classes, is_new, ctx);
-                    }
-                }
+    if res.is_ok() && ctx.history {
+        let classes = if cmd == IndvOp::Remove {
+            prev_state.get_literals("rdf:type")
+        } else {
+            new_state.get_literals("rdf:type")
+        };
+        if let Some(classes) = classes {
+            if is_exportable(_module, ctx, &classes) {
+                let date = date.map(|timestamp| NaiveDateTime::from_timestamp(timestamp, 0));
+                res = history::export(&mut new_state, &mut prev_state, &classes, cmd == IndvOp::Remove, ctx.last_op_id, date, ctx);
             }
         }
     }
-    Ok(true)
+
+    if let Err(e) = res {
+        // The changes collected for the element are dropped, so they are redriven together with the history
+        ctx.batch.truncate(start);
+        ctx.dead_letter.push(&uri, &serialize_element(queue_element), e);
+        return Ok(false);
+    }
+
+    // The change and its history are written or rolled back together
+    merge_ops(ctx, start, &uri);
+    if let Some(indv_ops) = ctx.batch.get_mut(start) {
+        indv_ops.source = Source::States {
+            cmd: queue_element.get_first_integer("cmd"),
+            op_id: ctx.last_op_id,
+            date,
+            new_state: if cmd == IndvOp::Remove {
+                None
+            } else {
+                Some(new_state)
+            },
+            prev_state: if is_new {
+                None
+            } else {
+                Some(prev_state)
+            },
+        };
+    }
+    // The queue position is committed after the batch is written
+    Ok(false)
 }
 
-fn export(new_state: &mut Individual, prev_state: &mut Individual, in_types: &[String], is_new: bool, ctx: &mut Context) -> Result<bool, PrepareError> {
+// Removes the rows of individual from every predicate table of its last state, or marks them as deleted
+fn remove(prev_state: &mut Individual, classes: &[String], remove_mode: RemoveMode, ctx: &mut Context) {
+    let uri = prev_state.get_id().to_string();
+
+    if classes.contains(&"v-s:Version".to_owned()) {
+        info!("skip version: {}", uri);
+        return;
+    }
+
+    let mut ops = vec![];
+    for predicate in prev_state.get_predicates() {
+        let predicate = ctx.db.ident_name(&predicate.to_lowercase());
+        if !ctx.tables.contains_key(&predicate) {
+            continue;
+        }
+        match remove_mode {
+            RemoveMode::Delete => ops.push(BatchOp::Delete(predicate, uri.clone())),
+            RemoveMode::MarkDeleted => ops.push(BatchOp::MarkDeleted(predicate, uri.clone())),
+        }
+    }
+
+    if !ops.is_empty() {
+        info!("remove uri = {}, mode = {:?}", uri, remove_mode);
+        push_ops(ctx, uri, ops);
+    }
+}
+
+// Returns Err if the export is aborted, the caller sends the element to the dead letter queue
+fn export(new_state: &mut Individual, prev_state: &mut Individual, in_types: &[String], is_new: bool, ctx: &mut Context) -> Result<(), &'static str> {
     let uri = new_state.get_id().to_string();
 
     let mut types = vec![];
@@ -186,25 +516,10 @@ fn export(new_state: &mut Individual, prev_state: &mut Individual, in_types: &[S
     let is_version = types.contains(&&"v-s:Version".to_owned());
     if is_version {
         info!("skip version: {}", uri);
-        return Ok(true);
+        return Ok(());
     }
 
-    let mut conn = match ctx.pool.get_conn() {
-        Err(e) => {
-            error!("failed to get connection, err = {:?}", e);
-            return Err(PrepareError::Recoverable);
-        },
-        Ok(conn) => conn,
-    };
-
-    let mut transaction = match conn.start_transaction(true, Some(mysql::IsolationLevel::ReadCommitted), None) {
-        Err(e) => {
-            error!("failed to start transaction, err = {:?}", e);
-            return Err(PrepareError::Recoverable);
-        },
-        Ok(transaction) => transaction,
-    };
-
+    let mut ops = vec![];
     let mut tr_error = false;
 
     // Remove previous item from DB
@@ -216,33 +531,20 @@ fn export(new_state: &mut Individual, prev_state: &mut Individual, in_types: &[S
             if is_exist_predicate_deleted || !compare_resources(&nsr, &psr) {
                 psr.unwrap().iter().for_each(|resource| {
                     if resource.order == 0 {
-                        let mut predicate = predicate.to_lowercase();
-                        predicate.truncate(64);
+                        let predicate = ctx.db.ident_name(&predicate.to_lowercase());
                         // Check or create table before delete
-                        if check_create_predicate_table(&mut ctx.tables, &predicate, resource, &mut transaction).is_err() {
+                        if check_create_predicate_table(&mut ctx.tables, &predicate, resource, false, ctx.db.as_mut()).is_err() {
                             error!("failed to to create table, export aborted, property = {}, uri = {}", predicate, uri);
                             tr_error = true;
                         }
-                        let query = format!("DELETE FROM `{}` WHERE doc_id = '{}'", predicate, uri);
-                        if let Err(e) = transaction.query(query) {
-                            error!("failed to delete individual, uri = {}, property table = {}, err = {:?}", uri, predicate, e);
-                            tr_error = true;
-                        }
+                        ops.push(BatchOp::Delete(predicate, uri.clone()));
                     }
                 });
             }
         });
     }
 
-    let created = match new_state.get_first_datetime("v-s:created") {
-        Some(timestamp) => format!("'{}'", NaiveDateTime::from_timestamp(timestamp, 0)),
-        None => String::from("NULL"),
-    };
-    let deleted = if is_deleted {
-        "1"
-    } else {
-        "NULL"
-    };
+    let created = new_state.get_first_datetime("v-s:created").map(|timestamp| NaiveDateTime::from_timestamp(timestamp, 0));
 
     types.iter().for_each(|class| {
         new_state.get_predicates().iter().for_each(|predicate| {
@@ -251,180 +553,79 @@ fn export(new_state: &mut Individual, prev_state: &mut Individual, in_types: &[S
 
             if is_exist_predicate_deleted || !compare_resources(&nsr, &psr) {
                 nsr.unwrap().iter().for_each(|resource| {
-                    let mut predicate = predicate.to_lowercase();
-                    predicate.truncate(64);
-                    // Check or create table before insert
-                    if resource.order == 0 && check_create_predicate_table(&mut ctx.tables, &predicate, resource, &mut transaction).is_err() {
+                    let predicate = ctx.db.ident_name(&predicate.to_lowercase());
+                    // Check or create table before insert, the column of the table is widened if the type of value differs
+                    if check_create_predicate_table(&mut ctx.tables, &predicate, resource, false, ctx.db.as_mut()).is_err() {
                         error!("failed to create table, export aborted, property = {}, uri = {}", predicate, uri);
                         tr_error = true;
                     }
 
-                    let uri = new_state.get_id();
-                    let value = match &resource.value {
-                        Value::Bool(true) => String::from("1"),
-                        Value::Bool(_) => String::from("0"),
-                        Value::Int(int_value) => int_value.to_string(),
-                        Value::Str(str_value, _lang) => format!("'{}'", str_value.replace('\'', "''").replace('\\', r#"\\"#)),
-                        Value::Uri(uri_value) => format!("'{}'", uri_value.replace('\'', "''").replace('\\', r#"\\"#)),
-                        Value::Num(_m, _e) => resource.get_float().to_string(),
-                        Value::Datetime(timestamp) => format!("'{}'", NaiveDateTime::from_timestamp(*timestamp, 0)),
-                        _ => String::from("NULL"),
-                    };
+                    let value = resource_to_value(resource);
 
-                    if value == "'v-s:Deletable'" {
-                        return;
+                    if let SqlValue::Str(str_value) = &value {
+                        if str_value == "v-s:Deletable" {
+                            return;
+                        }
                     }
 
-                    let lang = if resource.get_lang().is_some() {
-                        format!("'{}'", &resource.get_lang().to_string().to_uppercase())
-                    } else {
-                        String::from("'NO'")
+                    let row = PredicateRow {
+                        doc_id: uri.clone(),
+                        doc_type: class.to_string(),
+                        created,
+                        value,
+                        lang: resource_lang(resource),
+                        deleted: is_deleted,
                     };
-                    let query = format!(
-                        "INSERT INTO `{}` (doc_id, doc_type, created, value, lang, deleted) VALUES ('{}', '{}', {}, {}, {}, {})",
-                        predicate, uri, class, created, value, lang, deleted
-                    );
-                    //info!("Query: {}", query);
-                    if let Err(e) = transaction.query(query) {
-                        error!("failed to insert individual, uri = {}, property table = {}, err = {:?}", uri, predicate, e);
-                        tr_error = true;
-                    }
+                    ops.push(BatchOp::Insert(predicate, row));
                 });
             }
         });
     });
 
     if tr_error {
-        match transaction.rollback() {
-            Ok(_) => {
-                info!("transaction rolled back for `{}`", uri);
-                return Ok(true);
-            },
-            Err(e) => {
-                error!("failed to roll back transaction, uri = {}, err = {:?}", uri, e);
-                return Err(PrepareError::Fatal);
-            },
-        }
+        error!("export aborted, uri = {}", uri);
+        return Err("failed to create property table");
     }
 
-    match transaction.commit() {
-        Ok(_) => {
-            info!("Ok, uri = {}, types={:?}", uri, types);
-            Ok(true)
-        },
-        Err(e) => {
-            error!("failed to commit transaction, uri = {}, err = {:?}", uri, e);
-            Err(PrepareError::Fatal)
-        },
-    }
+    debug!("prepared uri = {}, types={:?}, statements = {}", uri, types, ops.len());
+    push_ops(ctx, uri, ops);
+    Ok(())
 }
 
+// Creates the predicate table, or the history table if history is set, or widens its value column
 fn check_create_predicate_table(
-    tables: &mut HashMap<String, bool>,
+    tables: &mut HashMap<String, ColumnType>,
     predicate: &str,
     resource: &Resource,
-    transaction: &mut mysql::Transaction,
+    history: bool,
+    db: &mut dyn SqlDialect,
 ) -> Result<(), &'static str> {
-    if tables.contains_key(predicate) {
-        return Ok(());
-    }
-    let mut sql_type = "";
-    let mut sql_value_index = ", INDEX civ(`value`)";
-    match &resource.rtype {
-        DataType::Boolean => sql_type = "BOOL",
-        DataType::Datetime => sql_type = "DATETIME",
-        DataType::Decimal => sql_type = "DECIMAL (14,4)",
-        DataType::Integer => sql_type = "INTEGER",
-        DataType::String => {
-            sql_type = "TEXT";
-            sql_value_index = "";
+    let column_type = match ColumnType::from_data_type(&resource.rtype) {
+        Some(column_type) => column_type,
+        None => {
+            error!("unsupported property value type: {:#?}", resource.rtype);
+            return Err("unsupported property value type");
         },
-        DataType::Uri => sql_type = "CHAR(128)",
-        _unsupported => error!("unsupported property value type: {:#?}", _unsupported),
-    }
-    let query = format!(
-        "CREATE TABLE `{}` ( \
-         `ID` BIGINT NOT NULL AUTO_INCREMENT, \
-         `doc_id` CHAR(128) NOT NULL, \
-         `doc_type` CHAR(128) NOT NULL, \
-         `created` DATETIME NULL, \
-         `value` {} NULL, \
-         `lang` CHAR(2) NULL, \
-         `deleted` BOOL NULL, \
-         PRIMARY KEY (`ID`), \
-         INDEX c1(`doc_id`), INDEX c2(`doc_type`), INDEX c3 (`created`), INDEX c4(`lang`) {} \
-         ) ENGINE=MyISAM DEFAULT CHARSET=utf8 COLLATE=utf8_bin;",
-        predicate, sql_type, sql_value_index
-    );
-
-    match transaction.query(query) {
-        Ok(_) => {
-            tables.insert(predicate.to_owned(), true);
-            Ok(())
-        },
-        Err(e) => {
-            error!("failed to create property table, err = {}", e);
-            Err("failed to create property table")
+    };
+    match tables.get(predicate) {
+        None => {
+            if history {
+                db.create_history_table(predicate, column_type)?;
+            } else {
+                db.create_predicate_table(predicate, column_type)?;
+            }
+            tables.insert(predicate.to_owned(), column_type);
         },
-    }
-}
-
-fn read_tables(pool: &mysql::Pool) -> Result<HashMap<String, bool>, &'static str> {
-    let mut tables: HashMap<String, bool> = HashMap::new();
-    if let Ok(result) = pool.prep_exec("SELECT TABLE_NAME FROM information_schema.tables;", ()) {
-        result.for_each(|row| {
-            let name: String = row.unwrap().get(0).unwrap();
-            tables.insert(name, true);
-        });
-        debug!("Existing tables: {:?}", tables);
-        return Ok(tables);
-    }
-    Err("failed to read existing tables")
-}
-
-fn connect_to_mysql(backend: &mut Backend, tries: i64, timeout: u64) -> Result<mysql::Pool, &'static str> {
-    if let Some(node) = backend.get_individual("cfg:standart_node", &mut Individual::default()) {
-        if let Some(v) = node.get_literals("v-s:push_individual_by_event") {
-            for el in v {
-                let mut connection = Individual::default();
-                if backend.storage.get_individual(&el, &mut connection) == ResultCode::Ok && !connection.is_exists_bool("v-s:deleted", true) {
-                    if let Some(transport) = connection.get_first_literal("v-s:transport") {
-                        if transport == "mysql" {
-                            info!("found configuration to connect to MySQL: {}", connection.get_id());
-                            let host = connection.get_first_literal("v-s:host").unwrap_or_default();
-                            let port = connection.get_first_integer("v-s:port").unwrap_or(3306) as u16;
-                            let login = connection.get_first_literal("v-s:login").unwrap();
-                            let pass = connection.get_first_literal("v-s:password").unwrap();
-                            let db = connection.get_first_literal("v-s:sql_database").unwrap();
-                            info!("trying to connect to mysql, host: {}, port: {}, login: {}, pass: {}, db: {}", host, port, login, pass, db);
-                            let mut builder = mysql::OptsBuilder::new();
-                            builder.ip_or_hostname(Some(host)).tcp_port(port).user(Some(login)).pass(Some(pass)).db_name(Some(db));
-                            let opts: mysql::Opts = builder.into();
-                            match mysql::Pool::new(opts) {
-                                Ok(pool) => {
-                                    info!("connection to MySQL established successfully");
-                                    return Ok(pool);
-                                },
-                                Err(e) => {
-                                    error!("failed to connect to MySQL, err = {:?}", e);
-                                    return Err("failed to connect to MySQL");
-                                },
-                            }
-                        }
-                    }
-                }
+        Some(existing) => {
+            let widened = existing.widen(column_type);
+            if widened != *existing {
+                warn!("type conflict in property table {}: column type = {:?}, value type = {:?}, widen column to {:?}", predicate, existing, column_type, widened);
+                db.widen_column(predicate, "value", *existing, widened)?;
+                tables.insert(predicate.to_owned(), widened);
             }
-        }
-    }
-    if tries != 0 {
-        let tries = tries - 1;
-        thread::sleep(time::Duration::from_millis(timeout));
-        error!("failed to find configuration, retry.");
-        connect_to_mysql(backend, tries, timeout)
-    } else {
-        error!("failed to find configuration to connect to mysql");
-        Err("failed to find configuration to connect to mysql")
+        },
     }
+    Ok(())
 }
 
 fn compare_resources(opt1: &Option<Vec<Resource>>, opt2: &Option<Vec<Resource>>) -> bool {
@@ -434,3 +635,24 @@ fn compare_resources(opt1: &Option<Vec<Resource>>, opt2: &Option<Vec<Resource>>)
         _ => false,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv1a_hash_matches_reference_values() {
+        assert_eq!(fnv1a_hash(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a_hash("a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a_hash("foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn fnv1a_hash_spreads_uris_over_shards() {
+        let mut counts = [0; 4];
+        for idx in 0..1000 {
+            counts[(fnv1a_hash(&format!("d:individual_{}", idx)) % 4) as usize] += 1;
+        }
+        assert!(counts.iter().all(|count| *count > 150), "uneven shards: {:?}", counts);
+    }
+}
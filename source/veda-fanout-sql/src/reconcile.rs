@@ -0,0 +1,361 @@
+use crate::backfill::{backfill_element, backfill_individual, read_exportable_page};
+use crate::batch::BatchOp;
+use crate::dialect::{resource_lang, resource_to_value, ColumnType, SqlValue};
+use crate::{flush_batch, is_exportable, is_own_shard, push_ops, Context, RemoveMode, TableMode};
+use chrono::NaiveDateTime;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::process;
+use v_common::module::veda_backend::Backend;
+use v_common::onto::individual::Individual;
+use v_common::v_api::obj::ResultCode;
+
+// Row of the predicate table without doc_id: doc_type, value, lang
+type ValueRow = (String, String, String);
+
+// Count of doc_id read from the predicate table at once by the reverse pass
+const DOC_ID_PAGE_SIZE: usize = 1000;
+
+#[derive(Default)]
+struct Stats {
+    individuals: usize,
+    mismatched: usize,
+    missing: usize,
+    extra: usize,
+    differing: usize,
+    orphaned: usize,
+    stale: usize,
+}
+
+// Compares the rows of the predicate tables with the exportable individuals of storage and writes the differences to the CSV report.
+// Then the predicate tables are read back: the rows of individuals absent in storage or not exportable anymore are reported as orphaned,
+// the rows of predicates absent in individual as stale
+pub fn reconcile(backend: &mut Backend, ctx: &mut Context, report_path: &str, repair: bool) {
+    if ctx.table_mode != TableMode::Predicate {
+        error!("reconciliation supports the predicate tables only");
+        process::exit(101);
+    }
+
+    let mut report = match File::create(report_path) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("failed to create report {}, err = {:?}", report_path, e);
+            process::exit(101);
+        },
+    };
+    if let Err(e) = writeln!(report, "status,uri,table,doc_type,lang,expected,actual") {
+        error!("failed to write report, err = {:?}", e);
+        process::exit(101);
+    }
+
+    info!("start reconciliation, report = {}, repair = {}", report_path, repair);
+    let mut stats = Stats::default();
+    let mut pos = 0;
+    loop {
+        let res = read_exportable_page(backend, pos);
+        if res.result_code != ResultCode::Ok {
+            error!("failed to read individuals for reconciliation, position = {}, err = {:?}", pos, res.result_code);
+            process::exit(101);
+        }
+        if res.result.is_empty() {
+            break;
+        }
+
+        for id in res.result.iter() {
+            pos += 1;
+            if !is_own_shard(ctx, id) {
+                continue;
+            }
+            let mut indv = Individual::default();
+            if backend.storage.get_individual(id, &mut indv) != ResultCode::Ok {
+                continue;
+            }
+            indv.parse_all();
+
+            let classes = indv.get_literals("rdf:type").unwrap_or_default();
+            if classes.contains(&"v-s:Version".to_owned()) || !is_exportable(backend, ctx, &classes) {
+                continue;
+            }
+            stats.individuals += 1;
+
+            let lines = match compare_individual(&mut indv, &classes, ctx) {
+                Ok(lines) => lines,
+                Err(_) => process::exit(101),
+            };
+            if lines.is_empty() {
+                continue;
+            }
+
+            stats.mismatched += 1;
+            write_lines(&mut report, &lines, &mut stats);
+
+            if repair {
+                ctx.source = backfill_element(&mut indv);
+                backfill_individual(backend, ctx, &mut indv);
+            }
+        }
+
+        if flush_batch(ctx).is_err() {
+            error!("failed to repair individuals, position = {}", pos);
+            process::exit(101);
+        }
+        info!("reconciliation position = {}, individuals = {}, mismatched = {}", pos, stats.individuals, stats.mismatched);
+    }
+
+    for table in ctx.tables.keys().cloned().collect::<Vec<String>>() {
+        reconcile_table(backend, ctx, &table, &mut report, repair, &mut stats);
+    }
+
+    info!(
+        "reconciliation is complete, individuals = {}, mismatched = {}, missing rows = {}, extra rows = {}, differing values = {}, orphaned rows = {}, stale rows = {}",
+        stats.individuals, stats.mismatched, stats.missing, stats.extra, stats.differing, stats.orphaned, stats.stale
+    );
+}
+
+// Reverse pass over the predicate table, reports the rows no individual of storage accounts for. With repair the orphaned rows
+// are removed by the remove mode and the stale rows are deleted, as export does when the predicate is removed from individual
+fn reconcile_table(backend: &mut Backend, ctx: &mut Context, table: &str, report: &mut File, repair: bool, stats: &mut Stats) {
+    // The rows marked as deleted stay in the table after the removal of individual
+    let skip_deleted = ctx.remove_mode == RemoveMode::MarkDeleted;
+    let mut after = String::new();
+    loop {
+        let doc_ids = match ctx.db.select_doc_ids(table, &after, DOC_ID_PAGE_SIZE, skip_deleted) {
+            Ok(doc_ids) => doc_ids,
+            Err(_) => process::exit(101),
+        };
+        let doc_id = match doc_ids.last() {
+            Some(doc_id) => doc_id.to_owned(),
+            None => break,
+        };
+
+        for uri in doc_ids.iter() {
+            if !is_own_shard(ctx, uri) {
+                continue;
+            }
+            let status = match row_status(backend, ctx, table, uri) {
+                Some(status) => status,
+                None => continue,
+            };
+
+            let rows = match ctx.db.select_values(table, uri) {
+                Ok(rows) => rows,
+                Err(_) => process::exit(101),
+            };
+            let lines: Vec<[String; 7]> = rows
+                .into_iter()
+                .map(|(doc_type, value, lang)| [status.to_owned(), uri.to_owned(), table.to_owned(), doc_type, lang, String::new(), value.unwrap_or_default()])
+                .collect();
+            write_lines(report, &lines, stats);
+
+            if repair {
+                let op = if status == "orphaned" && ctx.remove_mode == RemoveMode::MarkDeleted {
+                    BatchOp::MarkDeleted(table.to_owned(), uri.to_owned())
+                } else {
+                    BatchOp::Delete(table.to_owned(), uri.to_owned())
+                };
+                // There is no queue element for the rows, if the repair fails they are found by the next reconciliation
+                ctx.source = vec![];
+                push_ops(ctx, uri.to_owned(), vec![op]);
+            }
+        }
+
+        if flush_batch(ctx).is_err() {
+            error!("failed to repair property table {}", table);
+            process::exit(101);
+        }
+        info!("reconciliation of property table {}, position = {}, orphaned = {}, stale = {}", table, doc_id, stats.orphaned, stats.stale);
+        after = doc_id;
+    }
+}
+
+// Returns orphaned if the individual is absent in storage or is not exported, stale if it has no predicate of the table, None if the rows are expected
+fn row_status(backend: &mut Backend, ctx: &mut Context, table: &str, uri: &str) -> Option<&'static str> {
+    let mut indv = Individual::default();
+    if backend.storage.get_individual(uri, &mut indv) != ResultCode::Ok {
+        return Some("orphaned");
+    }
+    indv.parse_all();
+
+    let classes = indv.get_literals("rdf:type").unwrap_or_default();
+    if classes.contains(&"v-s:Version".to_owned()) || !is_exportable(backend, ctx, &classes) {
+        return Some("orphaned");
+    }
+    let has_predicate = indv
+        .get_predicates()
+        .iter()
+        .any(|predicate| ctx.db.ident_name(&predicate.to_lowercase()) == table && indv.get_resources(predicate).map_or(false, |resources| !resources.is_empty()));
+    if has_predicate {
+        None
+    } else {
+        Some("stale")
+    }
+}
+
+fn write_lines(report: &mut File, lines: &[[String; 7]], stats: &mut Stats) {
+    for line in lines.iter() {
+        match line[0].as_str() {
+            "missing" => stats.missing += 1,
+            "extra" => stats.extra += 1,
+            "orphaned" => stats.orphaned += 1,
+            "stale" => stats.stale += 1,
+            _ => stats.differing += 1,
+        }
+        let fields: Vec<String> = line.iter().map(|field| csv_field(field)).collect();
+        if let Err(e) = writeln!(report, "{}", fields.join(",")) {
+            error!("failed to write report, err = {:?}", e);
+            process::exit(101);
+        }
+    }
+}
+
+// Returns the lines of report: status, uri, table, doc_type, lang, expected, actual
+fn compare_individual(indv: &mut Individual, classes: &[String], ctx: &mut Context) -> Result<Vec<[String; 7]>, &'static str> {
+    let uri = indv.get_id().to_string();
+    let expected = expected_rows(indv, classes, ctx);
+
+    let mut lines = vec![];
+    for (table, expected_rows) in expected {
+        let column_type = ctx.tables.get(&table).copied();
+        let mut actual_rows: Vec<ValueRow> = vec![];
+        if let Some(column_type) = column_type {
+            for (doc_type, value, lang) in ctx.db.select_values(&table, &uri)? {
+                actual_rows.push((doc_type, normalize(&parse_value(value.as_deref(), column_type)), lang));
+            }
+        }
+        let expected_rows: Vec<ValueRow> = expected_rows
+            .into_iter()
+            .map(|(doc_type, value, lang)| {
+                let value = match column_type {
+                    Some(column_type) => normalize(&cast_value(&value, column_type)),
+                    None => value.to_text(),
+                };
+                (doc_type, value, lang)
+            })
+            .collect();
+
+        // Rows present on both sides are removed, the rest is missing or extra
+        let mut missing = vec![];
+        for row in expected_rows {
+            if let Some(idx) = actual_rows.iter().position(|actual| *actual == row) {
+                actual_rows.remove(idx);
+            } else {
+                missing.push(row);
+            }
+        }
+        let mut extra = actual_rows;
+
+        for (doc_type, value, lang) in missing {
+            // Missing and extra rows with the same doc_type and lang are reported as a differing value
+            if let Some(idx) = extra.iter().position(|(extra_type, _, extra_lang)| *extra_type == doc_type && *extra_lang == lang) {
+                let (_, actual, _) = extra.remove(idx);
+                lines.push(["differs".to_owned(), uri.clone(), table.clone(), doc_type, lang, value, actual]);
+            } else {
+                lines.push(["missing".to_owned(), uri.clone(), table.clone(), doc_type, lang, value, String::new()]);
+            }
+        }
+        for (doc_type, value, lang) in extra.drain(..) {
+            lines.push(["extra".to_owned(), uri.clone(), table.clone(), doc_type, lang, String::new(), value]);
+        }
+    }
+    Ok(lines)
+}
+
+// Rows which export writes for the new individual, grouped by the predicate table
+fn expected_rows(indv: &mut Individual, classes: &[String], ctx: &Context) -> HashMap<String, Vec<(String, SqlValue, String)>> {
+    let mut rows: HashMap<String, Vec<(String, SqlValue, String)>> = HashMap::new();
+    for class in classes.iter().filter(|class| *class != "v-s:Deletable") {
+        for predicate in indv.get_predicates() {
+            let table = ctx.db.ident_name(&predicate.to_lowercase());
+            for resource in indv.get_resources(&predicate).unwrap_or_default().iter() {
+                let value = resource_to_value(resource);
+                if let SqlValue::Str(str_value) = &value {
+                    if str_value == "v-s:Deletable" {
+                        continue;
+                    }
+                }
+                rows.entry(table.clone()).or_default().push((class.to_owned(), value, resource_lang(resource)));
+            }
+        }
+    }
+    rows
+}
+
+// Converts the expected value to the type of the column it is stored in
+fn cast_value(value: &SqlValue, column_type: ColumnType) -> SqlValue {
+    match (value, column_type) {
+        (SqlValue::Null, _) => SqlValue::Null,
+        (SqlValue::Bool(bool_value), ColumnType::Integer) => SqlValue::Int(*bool_value as i64),
+        (SqlValue::Bool(bool_value), ColumnType::Decimal) => SqlValue::Decimal(*bool_value as i64 as f64),
+        (SqlValue::Int(int_value), ColumnType::Decimal) => SqlValue::Decimal(*int_value as f64),
+        (_, ColumnType::Text) => SqlValue::Str(value.to_text()),
+        (SqlValue::Bool(bool_value), _) => SqlValue::Bool(*bool_value),
+        (SqlValue::Int(int_value), _) => SqlValue::Int(*int_value),
+        (SqlValue::Decimal(num_value), _) => SqlValue::Decimal(*num_value),
+        (SqlValue::Str(str_value), _) => SqlValue::Str(str_value.to_owned()),
+        (SqlValue::Datetime(datetime), _) => SqlValue::Datetime(*datetime),
+    }
+}
+
+// Parses the text of the value read from the column
+fn parse_value(value: Option<&str>, column_type: ColumnType) -> SqlValue {
+    let value = match value {
+        Some(value) => value,
+        None => return SqlValue::Null,
+    };
+    let parsed = match column_type {
+        ColumnType::Bool => Some(SqlValue::Bool(value == "1" || value == "true" || value == "t")),
+        ColumnType::Integer => value.parse::<i64>().ok().map(SqlValue::Int),
+        ColumnType::Decimal => value.parse::<f64>().ok().map(SqlValue::Decimal),
+        ColumnType::Datetime => NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").ok().map(SqlValue::Datetime),
+        ColumnType::Uri | ColumnType::Text => None,
+    };
+    parsed.unwrap_or_else(|| SqlValue::Str(value.to_owned()))
+}
+
+// Decimal values are compared with the precision of DECIMAL (14,4) column
+fn normalize(value: &SqlValue) -> String {
+    match value {
+        SqlValue::Decimal(num_value) => format!("{:.4}", num_value),
+        _ => value.to_text(),
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_rounds_decimals() {
+        assert_eq!(normalize(&SqlValue::Decimal(1.5)), "1.5000");
+        assert_eq!(normalize(&SqlValue::Decimal(2.123456)), "2.1235");
+        assert_eq!(normalize(&SqlValue::Int(7)), "7");
+        assert_eq!(normalize(&SqlValue::Str("v-s:Document".to_owned())), "v-s:Document");
+        assert_eq!(normalize(&SqlValue::Null), "");
+    }
+
+    #[test]
+    fn expected_and_stored_values_are_equal_after_normalize() {
+        assert_eq!(normalize(&cast_value(&SqlValue::Int(3), ColumnType::Decimal)), normalize(&parse_value(Some("3.0000"), ColumnType::Decimal)));
+        assert_eq!(normalize(&cast_value(&SqlValue::Bool(true), ColumnType::Integer)), normalize(&parse_value(Some("1"), ColumnType::Integer)));
+        assert_eq!(normalize(&cast_value(&SqlValue::Bool(true), ColumnType::Bool)), normalize(&parse_value(Some("t"), ColumnType::Bool)));
+        assert_eq!(normalize(&cast_value(&SqlValue::Int(5), ColumnType::Text)), normalize(&parse_value(Some("5"), ColumnType::Text)));
+        assert_eq!(normalize(&parse_value(None, ColumnType::Integer)), "");
+    }
+
+    #[test]
+    fn csv_field_quotes_special_chars() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("line\nbreak"), "\"line\nbreak\"");
+    }
+}